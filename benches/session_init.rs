@@ -0,0 +1,100 @@
+//! Compares `auth.jwt_session_init()` with the way it used to set the JWT
+//! runtime parameter: a `SET` statement parsed and executed through SPI.
+//!
+//! Run with `cargo bench --bench session_init`.
+
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use ed25519_dalek::{Signature, Signer, SigningKey};
+use jose_jwk::{jose_b64, Okp};
+use rand::rngs::OsRng;
+use serde_json::json;
+
+const ITERATIONS: usize = 10_000;
+
+fn main() -> ExitCode {
+    let sk = SigningKey::generate(&mut OsRng);
+    let options = format!("-c {NEON_AUTH_JWK_RUNTIME_PARAM}={}", create_jwk(&sk));
+
+    let result = pgrx_tests::run_test(Some(&options), None, vec![], |client| {
+        client.execute("select auth.init()", &[])?;
+        client.batch_execute(
+            "CREATE FUNCTION pg_temp.spi_session_init(jwt text) RETURNS void
+            LANGUAGE plpgsql AS $$
+            BEGIN
+                EXECUTE format('SET pg_session_jwt.jwt = %L', jwt);
+                PERFORM auth.session();
+            END
+            $$",
+        )?;
+
+        // token IDs must keep increasing across both runs
+        let mut jti = 0;
+        let mut jwts = || -> Vec<String> {
+            (0..ITERATIONS)
+                .map(|_| {
+                    jti += 1;
+                    sign_jwt(&sk, json!({"sub": "foo", "jti": jti}))
+                })
+                .collect()
+        };
+
+        let spi = run(
+            client,
+            "SELECT pg_temp.spi_session_init(jwt) FROM unnest($1::text[]) jwt",
+            &jwts(),
+        )?;
+        let direct = run(
+            client,
+            "SELECT auth.jwt_session_init(jwt) FROM unnest($1::text[]) jwt",
+            &jwts(),
+        )?;
+
+        println!("SET through SPI:       {:?}/iter", spi);
+        println!("set_config_option():   {:?}/iter", direct);
+
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(
+    client: &mut postgres::Client,
+    query: &str,
+    jwts: &[String],
+) -> Result<Duration, postgres::Error> {
+    let start = Instant::now();
+    client.execute(query, &[&jwts])?;
+    Ok(start.elapsed() / jwts.len() as u32)
+}
+
+static NEON_AUTH_JWK_RUNTIME_PARAM: &str = "pg_session_jwt.jwk";
+
+fn sign_jwt(sk: &SigningKey, payload: impl ToString) -> String {
+    let header = Base64UrlUnpadded::encode_string(br#"{"kid":1}"#);
+    let payload = Base64UrlUnpadded::encode_string(payload.to_string().as_bytes());
+
+    let message = format!("{header}.{payload}");
+    let sig: Signature = sk.sign(message.as_bytes());
+    let base64_sig = Base64UrlUnpadded::encode_string(&sig.to_bytes());
+    format!("{message}.{base64_sig}")
+}
+
+fn create_jwk(sk: &SigningKey) -> String {
+    let key = sk.verifying_key().to_bytes();
+    let key = jose_jwk::Key::Okp(Okp {
+        crv: jose_jwk::OkpCurves::Ed25519,
+        x: jose_b64::serde::Bytes::from(key.to_vec()),
+        d: None,
+    });
+    serde_json::to_string(&key).unwrap()
+}
@@ -33,6 +33,9 @@ use shutdown::add_shutdown_hook;
 
 type LogLines = Arc<Mutex<HashMap<String, Vec<String>>>>;
 
+/// The messages of the NOTICEs received by a client.
+pub type Notices = Arc<Mutex<Vec<String>>>;
+
 struct SetupState {
     installed: bool,
     loglines: LogLines,
@@ -201,6 +204,23 @@ pub fn run_test(
     }
 }
 
+/// A client connected as the superuser, e.g. to change the configuration of
+/// the cluster from within a `run_test` closure.
+pub fn superuser_client() -> eyre::Result<postgres::Client> {
+    let (client, _) = client(None, &get_pg_user())?;
+    Ok(client)
+}
+
+/// A client connected as the superuser with the given options, and the NOTICEs
+/// it receives, e.g. to check the messages an extension reports.
+pub fn superuser_client_with_notices(
+    options: Option<&str>,
+) -> eyre::Result<(postgres::Client, Notices)> {
+    let notices = Notices::default();
+    let (client, _) = connect(options, &get_pg_user(), Some(notices.clone()))?;
+    Ok((client, notices))
+}
+
 fn format_loglines(session_id: &str, loglines: &LogLines) -> String {
     let mut result = String::new();
 
@@ -267,6 +287,14 @@ fn get_pg_config() -> eyre::Result<PgConfig> {
 }
 
 fn client(options: Option<&str>, user: &str) -> eyre::Result<(postgres::Client, String)> {
+    connect(options, user, None)
+}
+
+fn connect(
+    options: Option<&str>,
+    user: &str,
+    notices: Option<Notices>,
+) -> eyre::Result<(postgres::Client, String)> {
     let pg_config = get_pg_config()?;
 
     let mut config = postgres::Config::new();
@@ -285,6 +313,12 @@ fn client(options: Option<&str>, user: &str) -> eyre::Result<(postgres::Client,
         config.options(options);
     }
 
+    if let Some(notices) = notices {
+        config.notice_callback(move |notice| {
+            notices.lock().unwrap().push(notice.message().to_string());
+        });
+    }
+
     let mut client = config
         .connect(postgres::NoTls)
         .wrap_err("Error connecting to Postgres")?;
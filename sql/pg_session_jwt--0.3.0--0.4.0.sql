@@ -0,0 +1,206 @@
+-- pg_session_jwt::auth::jwt_transaction_init
+CREATE OR REPLACE FUNCTION auth."jwt_transaction_init"("jwt" TEXT) RETURNS void
+STRICT LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'jwt_transaction_init_wrapper';
+
+-- pg_session_jwt::auth::jwt_session_reset
+CREATE OR REPLACE FUNCTION auth."jwt_session_reset"() RETURNS void
+STRICT LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'jwt_session_reset_wrapper';
+
+-- pg_session_jwt::auth::session_support
+CREATE OR REPLACE FUNCTION auth."session_support"("request" internal) RETURNS internal /* pgrx::datum::internal::Internal */
+STRICT LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'session_support_wrapper';
+
+-- pg_session_jwt::auth::user_id_support
+CREATE OR REPLACE FUNCTION auth."user_id_support"("request" internal) RETURNS internal /* pgrx::datum::internal::Internal */
+STRICT LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'user_id_support_wrapper';
+
+-- planner_support
+ALTER FUNCTION auth."session"() SUPPORT auth."session_support";
+ALTER FUNCTION auth."user_id"() SUPPORT auth."user_id_support";
+
+-- pg_session_jwt::auth::stats
+CREATE OR REPLACE FUNCTION auth."stats"() RETURNS TABLE (
+	"counter" TEXT,  /* alloc::string::String */
+	"sqlstate" TEXT,  /* core::option::Option<alloc::string::String> */
+	"reason" TEXT,  /* core::option::Option<alloc::string::String> */
+	"count" bigint  /* i64 */
+)
+STRICT VOLATILE
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'stats_wrapper';
+
+-- pg_session_jwt::auth::stats_reset
+CREATE OR REPLACE FUNCTION auth."stats_reset"() RETURNS void
+STRICT VOLATILE
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'stats_reset_wrapper';
+
+-- pg_session_jwt::auth::active_sessions
+CREATE OR REPLACE FUNCTION auth."active_sessions"() RETURNS TABLE (
+	"pid" INT,  /* i32 */
+	"sub" TEXT,  /* core::option::Option<alloc::string::String> */
+	"jti" bigint,  /* i64 */
+	"exp" timestamp with time zone,  /* core::option::Option<pgrx::datum::time_stamp_with_timezone::TimestampWithTimeZone> */
+	"validated_at" timestamp with time zone  /* pgrx::datum::time_stamp_with_timezone::TimestampWithTimeZone */
+)
+STRICT VOLATILE
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'active_sessions_wrapper';
+
+-- stats_views
+CREATE VIEW auth."stats" AS SELECT * FROM auth."stats"();
+GRANT SELECT ON auth."stats" TO PUBLIC;
+REVOKE ALL ON FUNCTION auth."stats_reset"() FROM PUBLIC;
+
+CREATE VIEW auth."active_sessions" AS SELECT * FROM auth."active_sessions"();
+GRANT SELECT ON auth."active_sessions" TO PUBLIC;
+
+-- pg_session_jwt::auth::session_info
+CREATE OR REPLACE FUNCTION auth."session_info"() RETURNS TABLE (
+	"mode" TEXT,  /* alloc::string::String */
+	"key_id" TEXT,  /* core::option::Option<alloc::string::String> */
+	"key_thumbprint" TEXT,  /* core::option::Option<alloc::string::String> */
+	"jti" bigint,  /* core::option::Option<i64> */
+	"iat" timestamp with time zone,  /* core::option::Option<pgrx::datum::time_stamp_with_timezone::TimestampWithTimeZone> */
+	"exp" timestamp with time zone,  /* core::option::Option<pgrx::datum::time_stamp_with_timezone::TimestampWithTimeZone> */
+	"expires_in" double precision,  /* core::option::Option<f64> */
+	"validated_at" timestamp with time zone,  /* core::option::Option<pgrx::datum::time_stamp_with_timezone::TimestampWithTimeZone> */
+	"from_cache" bool  /* bool */
+)
+STRICT VOLATILE
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'session_info_wrapper';
+
+-- pg_session_jwt::auth::check_jwt
+CREATE OR REPLACE FUNCTION auth."validate_jwt"(
+	"jwt" TEXT /* &str */
+) RETURNS TABLE (
+	"valid" bool,  /* bool */
+	"reason" TEXT,  /* core::option::Option<alloc::string::String> */
+	"header" jsonb,  /* core::option::Option<pgrx::datum::json::JsonB> */
+	"payload" jsonb  /* core::option::Option<pgrx::datum::json::JsonB> */
+)
+STRICT STABLE
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'check_jwt_wrapper';
+
+-- pg_session_jwt::auth::jwt_decode_header
+CREATE OR REPLACE FUNCTION auth."jwt_decode_header"(
+	"jwt" TEXT /* &str */
+) RETURNS jsonb /* pgrx::datum::json::JsonB */
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'jwt_decode_header_wrapper';
+
+-- pg_session_jwt::auth::jwt_decode_payload
+CREATE OR REPLACE FUNCTION auth."jwt_decode_payload"(
+	"jwt" TEXT /* &str */
+) RETURNS jsonb /* pgrx::datum::json::JsonB */
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'jwt_decode_payload_wrapper';
+
+-- pg_session_jwt::auth::jwt_sign
+CREATE OR REPLACE FUNCTION auth."jwt_sign"(
+	"payload" jsonb, /* pgrx::datum::json::JsonB */
+	"key" jsonb /* pgrx::datum::json::JsonB */
+) RETURNS TEXT /* alloc::string::String */
+STRICT
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'jwt_sign_wrapper';
+
+-- jwt_sign_privileges
+REVOKE ALL ON FUNCTION auth."jwt_sign"(jsonb, jsonb) FROM PUBLIC;
+
+-- signing_keys
+-- private JWKs used by auth.issue_token(), only readable by superusers
+CREATE TABLE auth."signing_keys" (
+    "kid" TEXT PRIMARY KEY,
+    "jwk" jsonb NOT NULL,
+    "status" TEXT NOT NULL DEFAULT 'pending'
+        CHECK ("status" IN ('pending', 'active', 'retired')),
+    "created_at" timestamptz NOT NULL DEFAULT now(),
+    "activated_at" timestamptz,
+    "retired_at" timestamptz
+);
+CREATE UNIQUE INDEX "signing_keys_one_active" ON auth."signing_keys" ((true))
+    WHERE "status" = 'active';
+REVOKE ALL ON auth."signing_keys" FROM PUBLIC;
+-- the keys are data, which pg_dump must include
+SELECT pg_catalog.pg_extension_config_dump('auth.signing_keys', '');
+
+-- strictly increasing, as required by auth.jwt_session_init()
+CREATE SEQUENCE auth."token_id_seq";
+REVOKE ALL ON SEQUENCE auth."token_id_seq" FROM PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('auth.token_id_seq', '');
+
+CREATE FUNCTION auth."issue_token"(
+    "sub" TEXT,
+    "claims" jsonb DEFAULT '{}',
+    "ttl" interval DEFAULT '1 hour'
+) RETURNS TEXT
+VOLATILE SECURITY DEFINER
+SET search_path = pg_catalog, pg_temp
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    signing_key auth.signing_keys;
+    issued_at bigint := floor(extract(epoch FROM now()));
+BEGIN
+    SELECT * INTO signing_key FROM auth.signing_keys WHERE status = 'active';
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'no active key in auth.signing_keys' USING ERRCODE = 'invalid_parameter_value';
+    END IF;
+
+    RETURN auth.jwt_sign(
+        coalesce(issue_token.claims, '{}') || jsonb_build_object(
+            'sub', issue_token.sub,
+            'iat', issued_at,
+            'exp', issued_at + floor(extract(epoch FROM issue_token.ttl)),
+            'jti', nextval('auth.token_id_seq')
+        ),
+        signing_key.jwk || jsonb_build_object('kid', signing_key.kid)
+    );
+END
+$$;
+REVOKE ALL ON FUNCTION auth."issue_token"(TEXT, jsonb, interval) FROM PUBLIC;
+
+-- pg_session_jwt::auth::jwks
+CREATE OR REPLACE FUNCTION auth."jwks"() RETURNS jsonb /* pgrx::datum::json::JsonB */
+STABLE STRICT SECURITY DEFINER
+SET search_path TO pg_catalog, pg_temp
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'jwks_wrapper';
+
+-- pg_session_jwt::auth::jwk_thumbprint
+CREATE OR REPLACE FUNCTION auth."jwk_thumbprint"(
+	"jwk" jsonb /* pgrx::datum::json::JsonB */
+) RETURNS TEXT /* alloc::string::String */
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c /* Rust */
+AS 'MODULE_PATHNAME', 'jwk_thumbprint_wrapper';
+
+-- pg_session_jwt::auth::verification_keys_changed
+CREATE OR REPLACE FUNCTION auth."verification_keys_changed"()
+	RETURNS TRIGGER
+	LANGUAGE c
+	AS 'MODULE_PATHNAME', 'verification_keys_changed_wrapper';
+
+-- verification_keys
+-- public JWKs used when pg_session_jwt.jwks_table is on, as published by auth.jwks()
+CREATE TABLE auth."verification_keys" (
+    "kid" TEXT PRIMARY KEY,
+    "jwk" jsonb NOT NULL,
+    "created_at" timestamptz NOT NULL DEFAULT now()
+);
+GRANT SELECT ON auth."verification_keys" TO PUBLIC;
+-- the keys are data, which pg_dump must include
+SELECT pg_catalog.pg_extension_config_dump('auth.verification_keys', '');
+
+CREATE TRIGGER "verification_keys_changed"
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON auth."verification_keys"
+FOR EACH STATEMENT EXECUTE FUNCTION auth."verification_keys_changed"();
@@ -0,0 +1,222 @@
+//! Audit events, written to the server log as single-line JSON.
+
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use pgrx::pg_sys;
+use pgrx::prelude::*;
+use serde_json::{json, Value};
+
+use crate::gucs::{
+    AuditLogLevel, AuditLogOn, NEON_AUTH_AUDIT_LOG_CLAIMS, NEON_AUTH_AUDIT_LOG_FAILURES_PER_SECOND,
+    NEON_AUTH_AUDIT_LOG_LEVEL, NEON_AUTH_AUDIT_LOG_ON, NEON_AUTH_AUDIT_LOG_REDACT,
+};
+
+type Object = serde_json::Map<String, Value>;
+
+/// Claims which are part of every event.
+const CLAIMS: [&str; 4] = ["sub", "aud", "iss", "jti"];
+
+thread_local! {
+    static FAILURES: RefCell<RateLimit> = const {
+        RefCell::new(RateLimit {
+            window_start: None,
+            events: 0,
+            suppressed: 0,
+        })
+    };
+}
+
+/// A JWT which is being validated, decoded before its signature is verified.
+#[derive(Default)]
+pub struct UnverifiedJwt<'a> {
+    pub header: Option<&'a Object>,
+    pub payload: Option<&'a Object>,
+    /// The thumbprint of the key the JWT is verified with.
+    pub key_thumbprint: Option<&'a str>,
+}
+
+/// Audit a JWT which was validated, or read back from the cache.
+pub fn validated_jwt(header: &Object, payload: &Object, key_thumbprint: &str, from_cache: bool) {
+    if !enabled(from_cache) {
+        return;
+    }
+    let event_type = if from_cache {
+        "jwt_session_access"
+    } else {
+        "jwt_validated"
+    };
+    let mut event = event(event_type, None);
+    add_claims(&mut event, Some(header), Some(payload));
+    event.insert("key_thumbprint".into(), key_thumbprint.into());
+    emit(event);
+}
+
+/// Audit the claims read from `request.jwt.claims`, or read back from the cache.
+pub fn guc_claims(claims: Option<&Object>, from_cache: bool) {
+    if !enabled(from_cache) {
+        return;
+    }
+    let reason = claims
+        .is_none()
+        .then_some("no JWT claims found in request.jwt.claims");
+    let mut event = event("guc_claims", reason);
+    add_claims(&mut event, None, claims);
+    emit(event);
+}
+
+/// Audit a JWT which failed validation. Its claims can't be trusted, but help
+/// to trace where it came from.
+pub fn failed_jwt(token: &UnverifiedJwt, reason: &str) {
+    if NEON_AUTH_AUDIT_LOG_LEVEL.get() == AuditLogLevel::Off {
+        return;
+    }
+    let per_second = NEON_AUTH_AUDIT_LOG_FAILURES_PER_SECOND.get();
+    let Some(suppressed) = FAILURES.with_borrow_mut(|failures| failures.admit(per_second)) else {
+        return;
+    };
+    let mut event = event("jwt_validated", Some(reason));
+    add_claims(&mut event, token.header, token.payload);
+    event.insert("key_thumbprint".into(), token.key_thumbprint.into());
+    if suppressed > 0 {
+        event.insert("suppressed".into(), suppressed.into());
+    }
+    emit(event);
+}
+
+/// Limits the number of failure events per second, so that a client sending
+/// invalid tokens in a loop can't flood the server log.
+struct RateLimit {
+    window_start: Option<Instant>,
+    events: i32,
+    suppressed: u64,
+}
+
+impl RateLimit {
+    /// Whether an event can be written now, with the number of events
+    /// suppressed since the last one.
+    fn admit(&mut self, per_second: i32) -> Option<u64> {
+        let now = Instant::now();
+        if self
+            .window_start
+            .is_none_or(|start| now.duration_since(start) >= Duration::from_secs(1))
+        {
+            self.window_start = Some(now);
+            self.events = 0;
+        }
+
+        if self.events < per_second {
+            self.events += 1;
+            Some(std::mem::take(&mut self.suppressed))
+        } else {
+            self.suppressed += 1;
+            None
+        }
+    }
+}
+
+fn enabled(from_cache: bool) -> bool {
+    NEON_AUTH_AUDIT_LOG_LEVEL.get() != AuditLogLevel::Off
+        && (!from_cache || NEON_AUTH_AUDIT_LOG_ON.get() == AuditLogOn::EveryAccess)
+}
+
+/// A new event, which failed when there's a `reason`.
+fn event(event_type: &str, reason: Option<&str>) -> Object {
+    let mut event = Object::new();
+    event.insert("event".into(), event_type.into());
+    match reason {
+        None => {
+            event.insert("outcome".into(), "success".into());
+        }
+        Some(reason) => {
+            event.insert("outcome".into(), "failure".into());
+            event.insert("reason".into(), reason.into());
+        }
+    }
+    event.insert("pid".into(), unsafe { pg_sys::MyProcPid }.into());
+    event.insert("database".into(), database_name().into());
+    event.insert("client_addr".into(), client_addr().into());
+    event
+}
+
+fn add_claims(event: &mut Object, header: Option<&Object>, payload: Option<&Object>) {
+    let redacted = setting_list(NEON_AUTH_AUDIT_LOG_REDACT.get());
+    let claim = |source: Option<&Object>, name: &str| match source.and_then(|c| c.get(name)) {
+        Some(_) if redacted.iter().any(|redacted| *redacted == name) => json!("[REDACTED]"),
+        Some(value) => value.clone(),
+        None => Value::Null,
+    };
+
+    for name in CLAIMS {
+        event.insert(name.into(), claim(payload, name));
+    }
+    event.insert("kid".into(), claim(header, "kid"));
+
+    let claims: Object = setting_list(NEON_AUTH_AUDIT_LOG_CLAIMS.get())
+        .into_iter()
+        .map(|name| (name.to_string(), claim(payload, name)))
+        .collect();
+    if !claims.is_empty() {
+        event.insert("claims".into(), claims.into());
+    }
+}
+
+/// Parse a comma-separated list setting.
+fn setting_list(setting: Option<&'static CStr>) -> Vec<&'static str> {
+    setting
+        .and_then(|setting| setting.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn database_name() -> Option<String> {
+    unsafe {
+        let name = pg_sys::get_database_name(pg_sys::MyDatabaseId);
+        if name.is_null() {
+            return None;
+        }
+        let database = CStr::from_ptr(name).to_string_lossy().into_owned();
+        pg_sys::pfree(name.cast());
+        Some(database)
+    }
+}
+
+/// The numeric address of the client, like `inet_client_addr()`. Unlike
+/// `remote_host`, it isn't replaced by the host name when `log_hostname` is on.
+fn client_addr() -> Option<String> {
+    unsafe {
+        let port = pg_sys::MyProcPort;
+        if port.is_null() {
+            return None;
+        }
+        let addr = std::ptr::addr_of!((*port).raddr.addr);
+        match (*addr).ss_family as u32 {
+            pg_sys::AF_INET => {
+                let addr = &*addr.cast::<pg_sys::sockaddr_in>();
+                Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).to_string())
+            }
+            pg_sys::AF_INET6 => {
+                let addr = &*addr.cast::<pg_sys::sockaddr_in6>();
+                let octets = std::mem::transmute::<pg_sys::in6_addr, [u8; 16]>(addr.sin6_addr);
+                Some(Ipv6Addr::from(octets).to_string())
+            }
+            pg_sys::AF_UNIX => Some("[local]".to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn emit(event: Object) {
+    let message = Value::Object(event).to_string();
+    match NEON_AUTH_AUDIT_LOG_LEVEL.get() {
+        AuditLogLevel::Off => {}
+        AuditLogLevel::Debug => debug1!("{message}"),
+        AuditLogLevel::Log => log!("{message}"),
+        AuditLogLevel::Notice => notice!("{message}"),
+    }
+}
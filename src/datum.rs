@@ -0,0 +1,88 @@
+use std::ptr::NonNull;
+
+use pgrx::callconv::{BoxRet, FcInfo};
+use pgrx::datum::Datum;
+use pgrx::pgrx_sql_entity_graph::metadata::{
+    ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
+};
+use pgrx::{pg_sys, varsize_any, IntoDatum, JsonB};
+
+/// A `jsonb` value which is already built, so returning it to Postgres
+/// doesn't go through `serde_json` and `jsonb_in` again.
+pub struct JsonbDatum(pg_sys::Datum);
+
+impl From<JsonB> for JsonbDatum {
+    fn from(json: JsonB) -> Self {
+        // JsonB always converts to a non-null datum
+        Self(json.into_datum().unwrap())
+    }
+}
+
+impl IntoDatum for JsonbDatum {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        Some(self.0)
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        pg_sys::JSONBOID
+    }
+}
+
+unsafe impl BoxRet for JsonbDatum {
+    unsafe fn box_into<'fcx>(self, fcinfo: &mut FcInfo<'fcx>) -> Datum<'fcx> {
+        fcinfo.return_raw_datum(self.0)
+    }
+}
+
+unsafe impl SqlTranslatable for JsonbDatum {
+    fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::literal("jsonb"))
+    }
+
+    fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::literal("jsonb")))
+    }
+}
+
+/// A `jsonb` value kept in `TopMemoryContext`, so it can be handed out
+/// by every call without being rebuilt.
+pub struct CachedJsonb(NonNull<pg_sys::varlena>);
+
+impl CachedJsonb {
+    pub fn new(json: JsonB) -> Self {
+        let JsonbDatum(datum) = json.into();
+        unsafe {
+            let jsonb = datum.cast_mut_ptr::<pg_sys::varlena>();
+            let cached = copy_varlena(jsonb, pg_sys::TopMemoryContext);
+            pg_sys::pfree(jsonb.cast());
+            Self(cached)
+        }
+    }
+
+    /// Copy the value into the current memory context.
+    pub fn get(&self) -> JsonbDatum {
+        unsafe {
+            let jsonb = copy_varlena(self.0.as_ptr(), pg_sys::CurrentMemoryContext);
+            JsonbDatum(pg_sys::Datum::from(jsonb.as_ptr()))
+        }
+    }
+}
+
+impl Drop for CachedJsonb {
+    fn drop(&mut self) {
+        unsafe { pg_sys::pfree(self.0.as_ptr().cast()) }
+    }
+}
+
+/// # Safety
+///
+/// `varlena` must point to a valid, detoasted varlena.
+unsafe fn copy_varlena(
+    varlena: *const pg_sys::varlena,
+    context: pg_sys::MemoryContext,
+) -> NonNull<pg_sys::varlena> {
+    let size = varsize_any(varlena);
+    let copy = pg_sys::MemoryContextAlloc(context, size);
+    std::ptr::copy_nonoverlapping(varlena.cast::<u8>(), copy.cast::<u8>(), size);
+    NonNull::new_unchecked(copy.cast())
+}
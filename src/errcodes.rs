@@ -0,0 +1,65 @@
+//! SQLSTATEs raised when a JWT or a JWK is rejected.
+//!
+//! Each reason has its own standard SQLSTATE, so that clients can tell why a
+//! JWT was rejected without parsing the message, e.g. to answer with 400, 401
+//! or 403.
+
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::PgSqlErrorCode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JwtErrorCode {
+    /// `22P02`: the JWT is malformed, or one of its claims has the wrong type.
+    InvalidJwt,
+    /// `28000`: the signature of the JWT doesn't match the JWK.
+    InvalidSignature,
+    /// `28P01`: the JWT is used after its `exp` claim.
+    Expired,
+    /// `55000`: the JWT is used before its `nbf` claim.
+    NotYetValid,
+    /// `23505`: the `jti` of the JWT isn't greater than the previous one.
+    Replayed,
+    /// `22023`: the JWK is malformed or not supported.
+    InvalidJwk,
+    /// `42501`: the JWT wasn't issued by the issuer of
+    /// `pg_session_jwt.oidc_discovery`.
+    UntrustedIssuer,
+}
+
+impl JwtErrorCode {
+    pub const fn pg_code(self) -> PgSqlErrorCode {
+        match self {
+            JwtErrorCode::InvalidJwt => PgSqlErrorCode::ERRCODE_INVALID_TEXT_REPRESENTATION,
+            JwtErrorCode::InvalidSignature => {
+                PgSqlErrorCode::ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION
+            }
+            JwtErrorCode::Expired => PgSqlErrorCode::ERRCODE_INVALID_PASSWORD,
+            JwtErrorCode::NotYetValid => PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            JwtErrorCode::Replayed => PgSqlErrorCode::ERRCODE_UNIQUE_VIOLATION,
+            JwtErrorCode::InvalidJwk => PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            JwtErrorCode::UntrustedIssuer => PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+        }
+    }
+}
+
+impl From<JwtErrorCode> for PgSqlErrorCode {
+    fn from(code: JwtErrorCode) -> Self {
+        code.pg_code()
+    }
+}
+
+/// The ERROR for a rejected JWT or JWK, usually with one of the SQLSTATEs of
+/// `JwtErrorCode`.
+#[track_caller]
+pub fn report(
+    code: impl Into<PgSqlErrorCode>,
+    message: &str,
+    detail: Option<&str>,
+    funcname: &'static str,
+) -> ErrorReport {
+    let report = ErrorReport::new(code.into(), message, funcname);
+    match detail {
+        Some(detail) => report.set_detail(detail),
+        None => report,
+    }
+}
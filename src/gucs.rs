@@ -1,12 +1,159 @@
 use pgrx::*;
-use std::ffi::CStr;
+use std::ffi::{c_char, c_void, CStr};
 
 pub static NEON_AUTH_JWK_RUNTIME_PARAM: &str = "pg_session_jwt.jwk";
 pub static NEON_AUTH_JWK: GucSetting<Option<&'static CStr>> =
     GucSetting::<Option<&'static CStr>>::new(None);
+pub static NEON_AUTH_JWKS_FILE_RUNTIME_PARAM: &str = "pg_session_jwt.jwks_file";
+pub static NEON_AUTH_JWKS_FILE: JwksFileSetting = JwksFileSetting;
+pub static NEON_AUTH_JWKS_TABLE_RUNTIME_PARAM: &str = "pg_session_jwt.jwks_table";
+pub static NEON_AUTH_JWKS_TABLE: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static NEON_AUTH_JWKS_URI_RUNTIME_PARAM: &str = "pg_session_jwt.jwks_uri";
+pub static NEON_AUTH_JWKS_URI: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+pub static NEON_AUTH_OIDC_DISCOVERY_RUNTIME_PARAM: &str = "pg_session_jwt.oidc_discovery";
+pub static NEON_AUTH_OIDC_DISCOVERY: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+pub static NEON_AUTH_JWKS_REFRESH_INTERVAL_RUNTIME_PARAM: &str =
+    "pg_session_jwt.jwks_refresh_interval";
+pub static NEON_AUTH_JWKS_REFRESH_INTERVAL: GucSetting<i32> = GucSetting::<i32>::new(300);
+pub static NEON_AUTH_JWKS_ALLOW_HTTP_RUNTIME_PARAM: &str = "pg_session_jwt.jwks_allow_http";
+pub static NEON_AUTH_JWKS_ALLOW_HTTP: GucSetting<bool> = GucSetting::<bool>::new(false);
 pub static NEON_AUTH_JWT_RUNTIME_PARAM: &str = "pg_session_jwt.jwt";
-pub static NEON_AUTH_JWT: GucSetting<Option<&'static CStr>> =
+pub static NEON_AUTH_JWT: JwtSetting = JwtSetting;
+pub static NEON_AUTH_AUDIT_LOG_LEVEL_RUNTIME_PARAM: &str = "pg_session_jwt.audit_log_level";
+pub static NEON_AUTH_AUDIT_LOG_LEVEL: GucSetting<AuditLogLevel> =
+    GucSetting::<AuditLogLevel>::new(AuditLogLevel::Log);
+pub static NEON_AUTH_AUDIT_LOG_ON_RUNTIME_PARAM: &str = "pg_session_jwt.audit_log_on";
+pub static NEON_AUTH_AUDIT_LOG_ON: GucSetting<AuditLogOn> =
+    GucSetting::<AuditLogOn>::new(AuditLogOn::Validate);
+pub static NEON_AUTH_AUDIT_LOG_CLAIMS_RUNTIME_PARAM: &str = "pg_session_jwt.audit_log_claims";
+pub static NEON_AUTH_AUDIT_LOG_CLAIMS: GucSetting<Option<&'static CStr>> =
     GucSetting::<Option<&'static CStr>>::new(None);
+pub static NEON_AUTH_AUDIT_LOG_REDACT_RUNTIME_PARAM: &str = "pg_session_jwt.audit_log_redact";
+pub static NEON_AUTH_AUDIT_LOG_REDACT: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+pub static NEON_AUTH_AUDIT_LOG_FAILURES_PER_SECOND_RUNTIME_PARAM: &str =
+    "pg_session_jwt.audit_log_failures_per_second";
+pub static NEON_AUTH_AUDIT_LOG_FAILURES_PER_SECOND: GucSetting<i32> = GucSetting::<i32>::new(10);
+
+/// Level of the audit log messages.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuditLogLevel {
+    Off,
+    Debug,
+    Log,
+    Notice,
+}
+
+/// When to write audit log messages.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuditLogOn {
+    /// When a JWT is validated, or new claims are read in fallback mode.
+    Validate,
+    /// Every time the session is accessed, even when it was cached.
+    EveryAccess,
+}
+
+// `GucEnum` is implemented by hand, because `PostgresGucEnum` uses the names
+// of the variants as labels, and ours are lowercase.
+
+impl GucEnum<AuditLogLevel> for AuditLogLevel {
+    fn from_ordinal(ordinal: i32) -> AuditLogLevel {
+        match ordinal {
+            0 => AuditLogLevel::Off,
+            1 => AuditLogLevel::Debug,
+            2 => AuditLogLevel::Log,
+            _ => AuditLogLevel::Notice,
+        }
+    }
+
+    fn to_ordinal(&self) -> i32 {
+        *self as i32
+    }
+
+    unsafe fn config_matrix(&self) -> *const pg_sys::config_enum_entry {
+        config_enum_entries(&[c"off", c"debug", c"log", c"notice"])
+    }
+}
+
+impl GucEnum<AuditLogOn> for AuditLogOn {
+    fn from_ordinal(ordinal: i32) -> AuditLogOn {
+        match ordinal {
+            0 => AuditLogOn::Validate,
+            _ => AuditLogOn::EveryAccess,
+        }
+    }
+
+    fn to_ordinal(&self) -> i32 {
+        *self as i32
+    }
+
+    unsafe fn config_matrix(&self) -> *const pg_sys::config_enum_entry {
+        config_enum_entries(&[c"validate", c"every_access"])
+    }
+}
+
+/// The options of an enum GUC, whose values are the positions of the labels,
+/// terminated by an entry without a name.
+unsafe fn config_enum_entries(labels: &[&'static CStr]) -> *const pg_sys::config_enum_entry {
+    let entries = PgMemoryContexts::TopMemoryContext
+        .palloc0_slice::<pg_sys::config_enum_entry>(labels.len() + 1);
+    for (entry, (val, label)) in entries.iter_mut().zip(labels.iter().enumerate()) {
+        entry.name = label.as_ptr();
+        entry.val = val as i32;
+        entry.hidden = false;
+    }
+    entries.as_ptr()
+}
+
+/// `pg_session_jwt.jwt` is defined directly through Postgres, because we need
+/// to know every time it changes and `GucRegistry` doesn't support hooks.
+pub struct JwtSetting;
+
+static mut NEON_AUTH_JWT_VALUE: *mut c_char = std::ptr::null_mut();
+
+impl JwtSetting {
+    pub fn get(&self) -> Option<&'static CStr> {
+        unsafe { current_jwt() }
+    }
+}
+
+unsafe fn current_jwt() -> Option<&'static CStr> {
+    let jwt = NEON_AUTH_JWT_VALUE;
+    (!jwt.is_null()).then(|| CStr::from_ptr(jwt))
+}
+
+#[pg_guard]
+unsafe extern "C" fn assign_jwt(newval: *const c_char, _extra: *mut c_void) {
+    // the variable still holds the previous value
+    let new_jwt = (!newval.is_null()).then(|| CStr::from_ptr(newval));
+    if current_jwt() != new_jwt {
+        crate::planner::invalidate_folded_plans();
+    }
+}
+
+/// `pg_session_jwt.jwks_file` is defined directly through Postgres, because
+/// the file must be read again on every reload of the configuration, even when
+/// the setting doesn't change.
+pub struct JwksFileSetting;
+
+static mut NEON_AUTH_JWKS_FILE_VALUE: *mut c_char = std::ptr::null_mut();
+
+impl JwksFileSetting {
+    pub fn get(&self) -> Option<&'static CStr> {
+        unsafe {
+            let path = NEON_AUTH_JWKS_FILE_VALUE;
+            (!path.is_null() && *path != 0).then(|| CStr::from_ptr(path))
+        }
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn assign_jwks_file(_newval: *const c_char, _extra: *mut c_void) {
+    // called for every setting of the configuration files on SIGHUP
+    crate::auth::invalidate_jwks_file();
+}
 
 pub fn init() {
     GucRegistry::define_string_guc(
@@ -18,12 +165,126 @@ pub fn init() {
         GucFlags::NOT_WHILE_SEC_REST | GucFlags::NO_RESET_ALL,
     );
 
+    GucRegistry::define_bool_guc(
+        NEON_AUTH_JWKS_TABLE_RUNTIME_PARAM,
+        "Verify JWTs with the keys of auth.verification_keys",
+        "The keys are cached by every session until the table changes",
+        &NEON_AUTH_JWKS_TABLE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        NEON_AUTH_JWKS_URI_RUNTIME_PARAM,
+        "URL of the JSON Web Key Set (JWKS) used for JWT validation",
+        "Or of an OIDC discovery document, fetched by a background worker",
+        &NEON_AUTH_JWKS_URI,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
     GucRegistry::define_string_guc(
-        NEON_AUTH_JWT_RUNTIME_PARAM,
-        "JSON Web Token (JWT) used for query authorization",
-        "Represents authenticated user session related claims like user ID",
-        &NEON_AUTH_JWT,
-        GucContext::Userset,
-        GucFlags::NOT_WHILE_SEC_REST,
+        NEON_AUTH_OIDC_DISCOVERY_RUNTIME_PARAM,
+        "OIDC discovery document of the issuer of the JWTs",
+        "URL, or file relative to the data directory, fetched by a background worker",
+        &NEON_AUTH_OIDC_DISCOVERY,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        NEON_AUTH_JWKS_REFRESH_INTERVAL_RUNTIME_PARAM,
+        "Maximum time between two fetches of the JWKS",
+        "Shorter when the response has a Cache-Control max-age",
+        &NEON_AUTH_JWKS_REFRESH_INTERVAL,
+        1,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_S,
     );
+
+    GucRegistry::define_bool_guc(
+        NEON_AUTH_JWKS_ALLOW_HTTP_RUNTIME_PARAM,
+        "Allow fetching the JWKS and the OIDC discovery document over plain HTTP",
+        "Only meant for tests and trusted networks, since the keys could be tampered with",
+        &NEON_AUTH_JWKS_ALLOW_HTTP,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        NEON_AUTH_AUDIT_LOG_LEVEL_RUNTIME_PARAM,
+        "Level of the audit log messages",
+        "One of off, debug, log or notice",
+        &NEON_AUTH_AUDIT_LOG_LEVEL,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        NEON_AUTH_AUDIT_LOG_ON_RUNTIME_PARAM,
+        "When to write audit log messages",
+        "validate logs once per validated JWT, every_access logs every access to the session",
+        &NEON_AUTH_AUDIT_LOG_ON,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        NEON_AUTH_AUDIT_LOG_CLAIMS_RUNTIME_PARAM,
+        "Extra JWT claims included in audit log messages",
+        "Comma-separated list of claim names",
+        &NEON_AUTH_AUDIT_LOG_CLAIMS,
+        GucContext::Suset,
+        GucFlags::from_bits_retain(pg_sys::GUC_LIST_INPUT as _),
+    );
+
+    GucRegistry::define_string_guc(
+        NEON_AUTH_AUDIT_LOG_REDACT_RUNTIME_PARAM,
+        "JWT claims redacted in audit log messages",
+        "Comma-separated list of claim names",
+        &NEON_AUTH_AUDIT_LOG_REDACT,
+        GucContext::Suset,
+        GucFlags::from_bits_retain(pg_sys::GUC_LIST_INPUT as _),
+    );
+
+    GucRegistry::define_int_guc(
+        NEON_AUTH_AUDIT_LOG_FAILURES_PER_SECOND_RUNTIME_PARAM,
+        "Maximum number of failed validations audited per second",
+        "Further failures are counted and reported with the next audited one",
+        &NEON_AUTH_AUDIT_LOG_FAILURES_PER_SECOND,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    unsafe {
+        pg_sys::DefineCustomStringVariable(
+            c"pg_session_jwt.jwks_file".as_ptr(),
+            c"JSON Web Key Set (JWKS) file used for JWT validation".as_ptr(),
+            c"Relative to the data directory, read again when the configuration is reloaded"
+                .as_ptr(),
+            std::ptr::addr_of_mut!(NEON_AUTH_JWKS_FILE_VALUE),
+            std::ptr::null(),
+            pg_sys::GucContext::PGC_SIGHUP,
+            0,
+            None,
+            Some(assign_jwks_file),
+            None,
+        );
+
+        pg_sys::DefineCustomStringVariable(
+            c"pg_session_jwt.jwt".as_ptr(),
+            c"JSON Web Token (JWT) used for query authorization".as_ptr(),
+            c"Represents authenticated user session related claims like user ID".as_ptr(),
+            std::ptr::addr_of_mut!(NEON_AUTH_JWT_VALUE),
+            std::ptr::null(),
+            pg_sys::GucContext::PGC_USERSET,
+            GucFlags::NOT_WHILE_SEC_REST.bits(),
+            None,
+            Some(assign_jwt),
+            None,
+        );
+    }
 }
@@ -0,0 +1,93 @@
+use pgrx::{is_a, pg_guard, pg_sys};
+use std::ffi::c_char;
+
+static mut PREV_PROCESS_UTILITY_HOOK: pg_sys::ProcessUtility_hook_type = None;
+
+// utils/inval.h isn't part of the pgrx bindings
+#[cfg_attr(target_os = "windows", link(name = "postgres"))]
+extern "C" {
+    fn CacheRegisterRelcacheCallback(
+        func: Option<unsafe extern "C" fn(arg: pg_sys::Datum, relid: pg_sys::Oid)>,
+        arg: pg_sys::Datum,
+    );
+    fn CacheInvalidateRelcacheByRelid(relid: pg_sys::Oid);
+}
+
+pub fn init() {
+    unsafe {
+        PREV_PROCESS_UTILITY_HOOK = pg_sys::ProcessUtility_hook;
+        pg_sys::ProcessUtility_hook = Some(process_utility_hook);
+        pg_sys::ffi::pg_guard_ffi_boundary(|| {
+            CacheRegisterRelcacheCallback(Some(relcache_callback), pg_sys::Datum::from(0usize))
+        });
+    }
+}
+
+/// Invalidate the relcache entry of a relation in every session, once the
+/// transaction commits.
+pub fn invalidate_relcache(relid: pg_sys::Oid) {
+    unsafe { pg_sys::ffi::pg_guard_ffi_boundary(|| CacheInvalidateRelcacheByRelid(relid)) }
+}
+
+/// Changes to `auth.verification_keys` invalidate its relcache entry, which
+/// tells us to read the keys again.
+#[pg_guard]
+unsafe extern "C" fn relcache_callback(_arg: pg_sys::Datum, relid: pg_sys::Oid) {
+    crate::auth::invalidate_jwks_table(relid);
+}
+
+/// Pooler reset queries (`DISCARD ALL`, `RESET ALL`) reset `pg_session_jwt.jwt`
+/// so we also drop the identity cached for it.
+#[pg_guard]
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn process_utility_hook(
+    pstmt: *mut pg_sys::PlannedStmt,
+    query_string: *const c_char,
+    read_only_tree: bool,
+    context: pg_sys::ProcessUtilityContext::Type,
+    params: pg_sys::ParamListInfo,
+    query_env: *mut pg_sys::QueryEnvironment,
+    dest: *mut pg_sys::DestReceiver,
+    qc: *mut pg_sys::QueryCompletion,
+) {
+    let resets_session = resets_session((*pstmt).utilityStmt);
+
+    match PREV_PROCESS_UTILITY_HOOK {
+        Some(prev) => prev(
+            pstmt,
+            query_string,
+            read_only_tree,
+            context,
+            params,
+            query_env,
+            dest,
+            qc,
+        ),
+        None => pg_sys::standard_ProcessUtility(
+            pstmt,
+            query_string,
+            read_only_tree,
+            context,
+            params,
+            query_env,
+            dest,
+            qc,
+        ),
+    }
+
+    if resets_session {
+        crate::auth::reset_session_state();
+    }
+}
+
+unsafe fn resets_session(stmt: *mut pg_sys::Node) -> bool {
+    if is_a(stmt, pg_sys::NodeTag::T_DiscardStmt) {
+        let stmt = stmt.cast::<pg_sys::DiscardStmt>();
+        (*stmt).target == pg_sys::DiscardMode::DISCARD_ALL
+    } else if is_a(stmt, pg_sys::NodeTag::T_VariableSetStmt) {
+        let stmt = stmt.cast::<pg_sys::VariableSetStmt>();
+        (*stmt).kind == pg_sys::VariableSetKind::VAR_RESET_ALL
+    } else {
+        false
+    }
+}
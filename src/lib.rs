@@ -1,5 +1,3 @@
-mod gucs;
-
 use pgrx::prelude::*;
 
 pgrx::pg_module_magic!();
@@ -15,33 +13,100 @@ macro_rules! error_code {
     }};
 }
 
+/// The `ErrorReport` of one of the SQLSTATEs of `errcodes::JwtErrorCode`.
+macro_rules! jwt_report {
+    ($errcode:expr, $message:expr $(,)?) => {
+        $crate::errcodes::report(
+            $errcode,
+            AsRef::<str>::as_ref(&$message),
+            None,
+            pgrx::pg_sys::function_name!(),
+        )
+    };
+    ($errcode:expr, $message:expr, $detail:expr $(,)?) => {
+        $crate::errcodes::report(
+            $errcode,
+            AsRef::<str>::as_ref(&$message),
+            Some(AsRef::<str>::as_ref(&$detail)),
+            pgrx::pg_sys::function_name!(),
+        )
+    };
+}
+
+/// Same as `error_code!`, with one of the SQLSTATEs of `errcodes::JwtErrorCode`.
+macro_rules! jwt_error {
+    ($errcode:expr, $message:expr $(, $detail:expr)? $(,)?) => {{
+        jwt_report!($errcode, $message $(, $detail)?).report(pgrx::PgLogLevel::ERROR);
+        unreachable!()
+    }};
+}
+
+// declared after the macros, so that they can use them
+mod audit;
+mod datum;
+mod errcodes;
+mod gucs;
+mod hooks;
+mod planner;
+mod sessions;
+mod stats;
+mod worker;
+mod xact;
+
 #[allow(non_snake_case)]
 #[pg_guard]
 pub unsafe extern "C" fn _PG_init() {
     gucs::init();
+    hooks::init();
+    xact::init();
+    stats::init();
+    sessions::init();
+    worker::init();
 }
 
 #[pg_schema]
 pub mod auth {
-    use std::cell::{OnceCell, RefCell};
+    use std::cell::{Cell, OnceCell, RefCell};
+    use std::ffi::{CStr, CString};
+    use std::rc::Rc;
 
+    use pgrx::pg_sys::panic::ErrorReport;
     use pgrx::prelude::*;
-    use pgrx::JsonB;
+    use pgrx::{pg_sys, to_timestamp, Internal, JsonB};
 
-    use ed25519_dalek::{Signature, VerifyingKey};
+    use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
     use jose_jwk::jose_b64;
 
     use base64ct::{Base64UrlUnpadded, Decoder, Encoding};
     use serde::de::DeserializeOwned;
+    use sha2::{Digest, Sha256};
 
+    use crate::audit::{self, UnverifiedJwt};
+    use crate::datum::{CachedJsonb, JsonbDatum};
+    use crate::errcodes::JwtErrorCode;
     use crate::gucs::{
-        NEON_AUTH_JWK, NEON_AUTH_JWK_RUNTIME_PARAM, NEON_AUTH_JWT, NEON_AUTH_JWT_RUNTIME_PARAM,
+        NEON_AUTH_JWK, NEON_AUTH_JWKS_FILE, NEON_AUTH_JWKS_FILE_RUNTIME_PARAM,
+        NEON_AUTH_JWKS_TABLE, NEON_AUTH_JWK_RUNTIME_PARAM, NEON_AUTH_JWT,
+        NEON_AUTH_JWT_RUNTIME_PARAM,
     };
+    use crate::planner;
+    use crate::sessions::{self, ActiveSession};
+    use crate::stats;
+    use crate::worker;
 
     type Object = serde_json::Map<String, serde_json::Value>;
 
+    /// PostgREST-compatible JWT claims, used when no JWK is defined.
+    ///
+    /// https://docs.postgrest.org/en/v12/references/transactions.html#request-headers-cookies-and-jwt-claims
+    const POSTGREST_JWT_CLAIMS_RUNTIME_PARAM: &CStr = c"request.jwt.claims";
+
     /// A octet key pair CFRG-curve key, as defined in [RFC 8037]
     ///
+    /// Other members, such as `x5t`, are ignored, but malformed values of the
+    /// honored ones are rejected, so that a key isn't used in a way its
+    /// metadata doesn't allow.
+    ///
     /// [RFC 8037]: https://www.rfc-editor.org/rfc/rfc8037
     #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
     pub struct Ed25519Okp {
@@ -52,6 +117,74 @@ pub mod auth {
 
         /// The public key.
         pub x: jose_b64::serde::Bytes<[u8; 32]>,
+
+        /// The key ID.
+        pub kid: Option<String>,
+
+        /// The intended use of the key.
+        #[serde(rename = "use")]
+        pub key_use: Option<KeyUse>,
+
+        /// The operations the key is intended for.
+        pub key_ops: Option<Vec<String>>,
+
+        /// The only algorithm the key may be used with.
+        pub alg: Option<String>,
+
+        /// The key can't be used before this time, in seconds since the unix
+        /// epoch.
+        pub nbf: Option<i64>,
+
+        /// The key can't be used after this time, in seconds since the unix
+        /// epoch.
+        pub exp: Option<i64>,
+    }
+
+    impl Ed25519Okp {
+        /// Turn the JWK into a key which verifies JWTs, if its metadata allows it.
+        fn into_verification_key(self) -> Result<Jwk, String> {
+            if self.key_use == Some(KeyUse::Enc) {
+                return Err(r#"the JWK is an encryption key ("use": "enc")"#.into());
+            }
+            if let Some(key_ops) = &self.key_ops {
+                if !key_ops.iter().any(|op| op == "verify") {
+                    return Err(r#"the "key_ops" of the JWK don't include "verify""#.into());
+                }
+            }
+            if let Some(alg) = &self.alg {
+                if alg != "EdDSA" {
+                    return Err(format!(
+                        "the JWK is bound to the unsupported {alg:?} algorithm"
+                    ));
+                }
+            }
+            let verifying_key = VerifyingKey::from_bytes(&self.x).map_err(|e| e.to_string())?;
+
+            Ok(Jwk {
+                alg: self.alg,
+                nbf: self.nbf,
+                exp: self.exp,
+                ..Jwk::new(verifying_key, self.kid)
+            })
+        }
+    }
+
+    /// An Ed25519 key pair, with its private key.
+    #[derive(serde::Deserialize)]
+    pub struct Ed25519OkpPrivate {
+        pub kty: Kty,
+
+        /// The CFRG curve.
+        pub crv: OkpCurves,
+
+        /// The public key.
+        pub x: jose_b64::serde::Bytes<[u8; 32]>,
+
+        /// The private key.
+        pub d: jose_b64::serde::Bytes<[u8; 32]>,
+
+        /// The key ID, copied into the header of the JWTs it signs.
+        pub kid: Option<String>,
     }
 
     /// The CFRG Curve.
@@ -68,13 +201,135 @@ pub mod auth {
         Ed25519,
     }
 
+    /// A JWK Set, as defined in [RFC 7517].
+    ///
+    /// [RFC 7517]: https://www.rfc-editor.org/rfc/rfc7517#section-5
+    #[derive(serde::Deserialize)]
+    struct JwkSet {
+        keys: Vec<Ed25519Okp>,
+    }
+
+    /// The intended use of a public key.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum KeyUse {
+        Sig,
+        Enc,
+    }
+
+    /// A key which verifies JWTs.
+    #[derive(Clone)]
+    struct Jwk {
+        verifying_key: VerifyingKey,
+        kid: Option<String>,
+        /// The RFC 7638 thumbprint, which identifies keys without a `kid`.
+        thumbprint: String,
+        /// The algorithm the key is bound to, which JWTs must use.
+        alg: Option<String>,
+        /// When the key becomes valid, in seconds since the unix epoch.
+        nbf: Option<i64>,
+        /// When the key expires, in seconds since the unix epoch.
+        exp: Option<i64>,
+    }
+
+    impl Jwk {
+        fn new(verifying_key: VerifyingKey, kid: Option<String>) -> Self {
+            Self {
+                thumbprint: thumbprint(&verifying_key),
+                verifying_key,
+                kid,
+                alg: None,
+                nbf: None,
+                exp: None,
+            }
+        }
+
+        /// The `kid` of the key, or its thumbprint when it doesn't have one.
+        fn key_id(&self) -> &str {
+            self.kid.as_deref().unwrap_or(&self.thumbprint)
+        }
+
+        /// Whether a JWT with this header may be signed by the key.
+        ///
+        /// The `kid` of the header must name the key, either by its `kid` or
+        /// by its thumbprint. Keys without a `kid` accept any `kid`, as they
+        /// used to.
+        fn matches(&self, header: Option<&Object>) -> bool {
+            let Some(header_kid) = header.and_then(|header| header.get("kid")) else {
+                return true;
+            };
+            let Some(kid) = &self.kid else {
+                return true;
+            };
+            let header_kid = header_kid.as_str();
+            header_kid == Some(kid.as_str()) || header_kid == Some(self.thumbprint.as_str())
+        }
+
+        /// The public JWK, as published in `auth.jwks()`.
+        fn to_json(&self) -> serde_json::Value {
+            serde_json::json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": Base64UrlUnpadded::encode_string(self.verifying_key.as_bytes()),
+                "kid": self.key_id(),
+                "alg": self.alg.as_deref().unwrap_or("EdDSA"),
+                "use": "sig",
+            })
+        }
+    }
+
+    /// A JWT which was successfully validated.
+    pub(crate) struct ValidatedJwt {
+        jwt: String,
+        header: Object,
+        payload: Object,
+        /// The payload, ready to be returned by `auth.session()`.
+        jsonb: CachedJsonb,
+        /// When the JWT was validated, in seconds since the unix epoch.
+        validated_at: f64,
+        /// The thumbprint of the key which verified the JWT.
+        key_thumbprint: String,
+    }
+
+    impl ValidatedJwt {
+        fn new(jwt: &str, header: Object, payload: Object, key: &Jwk) -> Self {
+            let jsonb = CachedJsonb::new(JsonB(serde_json::Value::Object(payload.clone())));
+            Self {
+                jwt: jwt.to_string(),
+                header,
+                payload,
+                jsonb,
+                validated_at: epoch(),
+                key_thumbprint: key.thumbprint.clone(),
+            }
+        }
+    }
+
     thread_local! {
-        static JWK: OnceCell<VerifyingKey> = const { OnceCell::new() };
-        static JWT: RefCell<Option<(String, Object)>> = const { RefCell::new(None) };
+        static JWK: OnceCell<Jwk> = const { OnceCell::new() };
+        /// The keys of `pg_session_jwt.jwks_file`, and whether the file must
+        /// be read again.
+        static JWKS_FILE: RefCell<Vec<Jwk>> = const { RefCell::new(Vec::new()) };
+        static JWKS_FILE_STALE: Cell<bool> = const { Cell::new(true) };
+        /// The keys of `auth.verification_keys`, with the number of
+        /// invalidations received before they were read.
+        static JWKS_TABLE: RefCell<Option<(u64, Vec<Jwk>)>> = const { RefCell::new(None) };
+        static JWKS_TABLE_INVALIDATIONS: Cell<u64> = const { Cell::new(0) };
+        static JWKS_TABLE_OID: Cell<pg_sys::Oid> = const { Cell::new(pg_sys::InvalidOid) };
+        /// The keys fetched by the background worker, with their generation.
+        static JWKS_REMOTE: RefCell<(u64, RemoteJwks)> = const {
+            RefCell::new((0, RemoteJwks { keys: Vec::new(), oidc: None }))
+        };
+        static JWT: RefCell<Option<Rc<ValidatedJwt>>> = const { RefCell::new(None) };
         static JTI: RefCell<i64> = const { RefCell::new(0) };
+        /// Cached JWT to go back to once the transaction which called
+        /// `jwt_transaction_init` ends.
+        static XACT_JWT: RefCell<Option<Option<Rc<ValidatedJwt>>>> = const { RefCell::new(None) };
+        /// Raw and parsed value of `request.jwt.claims`, used in fallback mode.
+        static CLAIMS: RefCell<Option<(Vec<u8>, Option<Rc<serde_json::Value>>)>> = const { RefCell::new(None) };
     }
 
-    fn get_jwk_guc() -> VerifyingKey {
+    fn get_jwk_guc() -> Jwk {
         let jwk = NEON_AUTH_JWK
             .get()
             .unwrap_or_else(|| {
@@ -86,116 +341,557 @@ pub mod auth {
             .to_bytes();
 
         JWK.with(|b| {
-            *b.get_or_init(|| {
+            b.get_or_init(|| {
                 let jwk: Ed25519Okp = serde_json::from_slice(jwk).unwrap_or_else(|e| {
-                    error_code!(
-                        PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
+                    jwt_error!(
+                        JwtErrorCode::InvalidJwk,
                         "pg_session_jwt.jwk requires an ES256 JWK",
                         e.to_string(),
                     )
                 });
 
-                VerifyingKey::from_bytes(&jwk.x).unwrap_or_else(|e| {
-                    error_code!(
-                        PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                        "pg_session_jwt.jwk requires an ES256 JWK",
-                        e.to_string()
+                jwk.into_verification_key().unwrap_or_else(|e| {
+                    jwt_error!(
+                        JwtErrorCode::InvalidJwk,
+                        "pg_session_jwt.jwk is not a signature verification key",
+                        e
                     )
                 })
             })
+            .clone()
         })
     }
 
-    /// Set the public key for this postgres session.
-    #[pg_extern]
-    pub fn init() {
-        get_jwk_guc();
+    /// Whether JWTs are verified with keys, rather than read from
+    /// `request.jwt.claims`.
+    fn jwk_mode() -> bool {
+        NEON_AUTH_JWK.get().is_some()
+            || NEON_AUTH_JWKS_FILE.get().is_some()
+            || NEON_AUTH_JWKS_TABLE.get()
+            || worker::enabled()
     }
 
-    fn verify_signature(key: &VerifyingKey, body: &str, sig: &str) {
-        let mut sig_bytes = [0; 64];
-        Base64UrlUnpadded::decode(sig, &mut sig_bytes).unwrap_or_else(|_| {
+    /// The keys which verify JWTs: the one of `pg_session_jwt.jwk`, then the
+    /// ones of `pg_session_jwt.jwks_file`, of `auth.verification_keys` and
+    /// the ones fetched from `pg_session_jwt.jwks_uri` or
+    /// `pg_session_jwt.oidc_discovery`.
+    fn configured_keys() -> Vec<Jwk> {
+        let mut keys = vec![];
+        if NEON_AUTH_JWK.get().is_some() {
+            keys.push(get_jwk_guc());
+        }
+        keys.extend(file_jwks());
+        if NEON_AUTH_JWKS_TABLE.get() {
+            keys.extend(table_jwks());
+        }
+        if worker::enabled() {
+            worker::ensure_started();
+            keys.extend(remote_jwks().keys);
+        }
+        keys
+    }
+
+    /// Like `configured_keys`, but at least one key is required.
+    fn verification_keys() -> Result<Vec<Jwk>, Rejection> {
+        let keys = configured_keys();
+        if keys.is_empty() {
+            if NEON_AUTH_JWKS_FILE.get().is_some()
+                || NEON_AUTH_JWKS_TABLE.get()
+                || worker::enabled()
+            {
+                return Err(Rejection::new(
+                    JwtErrorCode::InvalidJwk,
+                    "no JWK is configured to verify JWTs",
+                ));
+            }
+            return Err(Rejection::new(
+                PgSqlErrorCode::ERRCODE_NO_DATA,
+                "Missing runtime parameter: pg_session_jwt.jwk",
+            ));
+        }
+        Ok(keys)
+    }
+
+    /// The keys of `pg_session_jwt.jwks_file`.
+    ///
+    /// The file is read again once the configuration was reloaded. When it
+    /// can't be read, a WARNING is raised and the previous keys are kept.
+    fn file_jwks() -> Vec<Jwk> {
+        if JWKS_FILE_STALE.replace(false) {
+            match NEON_AUTH_JWKS_FILE.get().map(read_jwks_file) {
+                None => {
+                    JWKS_FILE.take();
+                }
+                Some(Ok(keys)) => {
+                    JWKS_FILE.replace(keys);
+                }
+                Some(Err(e)) => warning!(
+                    "could not load {NEON_AUTH_JWKS_FILE_RUNTIME_PARAM}, keeping the previous keys: {e}"
+                ),
+            }
+        }
+        JWKS_FILE.with_borrow(Clone::clone)
+    }
+
+    /// Read `pg_session_jwt.jwks_file` again the next time the keys are used.
+    pub(crate) fn invalidate_jwks_file() {
+        JWKS_FILE_STALE.set(true);
+    }
+
+    /// Read a JWK Set from a file of the data directory.
+    fn read_jwks_file(path: &CStr) -> Result<Vec<Jwk>, String> {
+        let jwks = read_data_file(&path.to_string_lossy())?;
+        let jwks: JwkSet = serde_json::from_slice(&jwks).map_err(|e| e.to_string())?;
+
+        jwks.keys
+            .into_iter()
+            .enumerate()
+            .map(|(i, jwk)| {
+                jwk.into_verification_key()
+                    .map_err(|e| format!("key {i}: {e}"))
+            })
+            .collect()
+    }
+
+    /// Read a file of the data directory, relative to it.
+    pub(crate) fn read_data_file(path: &str) -> Result<Vec<u8>, String> {
+        let data_dir = unsafe { pg_sys::DataDir };
+        if data_dir.is_null() {
+            return Err("the data directory is not known".into());
+        }
+        let data_dir = unsafe { CStr::from_ptr(data_dir) }.to_string_lossy();
+        let data_dir = std::fs::canonicalize(&*data_dir).map_err(|e| e.to_string())?;
+
+        // relative paths are relative to the data directory
+        let file = std::fs::canonicalize(data_dir.join(path))
+            .map_err(|e| format!("could not open \"{path}\": {e}"))?;
+        if !file.starts_with(&data_dir) {
+            return Err(format!("\"{path}\" is not in the data directory"));
+        }
+        std::fs::read(&file).map_err(|e| format!("could not read \"{path}\": {e}"))
+    }
+
+    /// The keys of `auth.verification_keys`, read again once the table changed.
+    fn table_jwks() -> Vec<Jwk> {
+        // counted before reading, so that an invalidation received while
+        // reading makes the next call read the table again
+        let invalidations = JWKS_TABLE_INVALIDATIONS.get();
+        let cached = JWKS_TABLE.with_borrow(|cached| match cached {
+            Some((read_after, keys)) if *read_after == invalidations => Some(keys.clone()),
+            _ => None,
+        });
+        if let Some(keys) = cached {
+            return keys;
+        }
+        let keys = read_jwks_table();
+        JWKS_TABLE.replace(Some((invalidations, keys.clone())));
+        keys
+    }
+
+    /// Read `auth.verification_keys` again the next time the keys are used,
+    /// if it's the relation which changed (`InvalidOid` meaning any relation).
+    /// Until the table was looked up, any relation may be it.
+    pub(crate) fn invalidate_jwks_table(relid: pg_sys::Oid) {
+        let table = JWKS_TABLE_OID.get();
+        if relid == pg_sys::InvalidOid || table == pg_sys::InvalidOid || relid == table {
+            JWKS_TABLE_INVALIDATIONS.set(JWKS_TABLE_INVALIDATIONS.get() + 1);
+        }
+    }
+
+    /// The keys published by the background worker, with the OIDC provider
+    /// they belong to.
+    #[derive(Clone)]
+    struct RemoteJwks {
+        keys: Vec<Jwk>,
+        oidc: Option<OidcProvider>,
+    }
+
+    /// The members of an OIDC discovery document which JWTs are checked
+    /// against.
+    #[derive(Clone, serde::Deserialize)]
+    struct OidcProvider {
+        issuer: String,
+        id_token_signing_alg_values_supported: Vec<String>,
+    }
+
+    #[derive(Default, serde::Deserialize)]
+    struct PublishedJwks {
+        keys: Vec<Ed25519Okp>,
+        oidc: Option<OidcProvider>,
+    }
+
+    /// The keys published by the background worker, parsed again once they
+    /// changed.
+    fn remote_jwks() -> RemoteJwks {
+        let generation = worker::generation();
+        JWKS_REMOTE.with_borrow_mut(|(cached_generation, remote)| {
+            if *cached_generation != generation {
+                // the worker only publishes supported keys
+                let PublishedJwks { keys, oidc } =
+                    serde_json::from_slice(&worker::jwks()).unwrap_or_default();
+                *remote = RemoteJwks {
+                    keys: keys
+                        .into_iter()
+                        .filter_map(|jwk| jwk.into_verification_key().ok())
+                        .collect(),
+                    oidc,
+                };
+                *cached_generation = generation;
+            }
+            remote.clone()
+        })
+    }
+
+    /// Check that a JWK fetched by the background worker can verify JWTs.
+    pub(crate) fn check_jwk(jwk: &serde_json::Value) -> Result<(), String> {
+        serde_json::from_value::<Ed25519Okp>(jwk.clone())
+            .map_err(|e| e.to_string())?
+            .into_verification_key()
+            .map(|_| ())
+    }
+
+    fn read_jwks_table() -> Vec<Jwk> {
+        let rows = Spi::connect(|client| {
+            let oid = client
+                .select("SELECT 'auth.verification_keys'::regclass::oid", None, None)?
+                .first()
+                .get_one::<pg_sys::Oid>()?;
+            JWKS_TABLE_OID.set(oid.unwrap_or(pg_sys::InvalidOid));
+
+            client
+                .select(
+                    "SELECT kid, jwk FROM auth.verification_keys ORDER BY kid",
+                    None,
+                    None,
+                )?
+                .map(|row| Ok((row.get_by_name("kid")?, row.get_by_name("jwk")?)))
+                .collect::<Result<Vec<(Option<String>, Option<JsonB>)>, pgrx::spi::Error>>()
+        })
+        .unwrap_or_else(|e| {
             error_code!(
-                PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                "invalid JWT signature encoding",
+                PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+                "could not read auth.verification_keys",
+                e.to_string(),
             )
         });
-        let sig = Signature::from_bytes(&sig_bytes);
 
-        key.verify_strict(body.as_bytes(), &sig)
-            .unwrap_or_else(|_| {
-                error_code!(
-                    PgSqlErrorCode::ERRCODE_CHECK_VIOLATION,
-                    "invalid JWT signature",
-                )
-            });
+        rows.into_iter()
+            .map(|(kid, jwk)| {
+                let jwk = jwk.map_or(serde_json::Value::Null, |jwk| jwk.0);
+                let key = serde_json::from_value::<Ed25519Okp>(jwk)
+                    .map_err(|e| e.to_string())
+                    .and_then(Ed25519Okp::into_verification_key)
+                    .unwrap_or_else(|e| {
+                        jwt_error!(
+                            JwtErrorCode::InvalidJwk,
+                            "auth.verification_keys requires Ed25519 public JWKs",
+                            format!("key {}: {e}", kid.as_deref().unwrap_or_default()),
+                        )
+                    });
+                // the kid of the table wins over the one of the JWK
+                Jwk {
+                    kid: kid.or(key.kid.clone()),
+                    ..key
+                }
+            })
+            .collect()
     }
 
-    fn verify_token_id(payload: &Object) -> i64 {
-        let jti = payload
-            .get("jti")
-            .and_then(|x| x.as_i64())
-            .unwrap_or_else(|| {
-                error_code!(
-                    PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                    "JWT payload must contain a valid 'jti' (JWT ID)",
-                )
-            });
+    /// Check the keys of `auth.verification_keys` once it changed, and make
+    /// every session read them again when the transaction commits.
+    #[pg_trigger]
+    pub fn verification_keys_changed<'a>(
+        trigger: &'a pgrx::PgTrigger<'a>,
+    ) -> Result<
+        Option<pgrx::heap_tuple::PgHeapTuple<'a, pgrx::AllocatedByRust>>,
+        pgrx::PgTriggerError,
+    > {
+        read_jwks_table();
+        crate::hooks::invalidate_relcache(trigger.relation()?.oid());
+        Ok(None)
+    }
 
-        JTI.with_borrow(|t| {
-            if jti <= *t {
-                error_code!(
-                    PgSqlErrorCode::ERRCODE_CHECK_VIOLATION,
-                    "Token ID must be strictly monotonically increasing."
-                );
+    pgrx::extension_sql!(
+        r#"
+-- public JWKs used when pg_session_jwt.jwks_table is on, as published by auth.jwks()
+CREATE TABLE auth."verification_keys" (
+    "kid" TEXT PRIMARY KEY,
+    "jwk" jsonb NOT NULL,
+    "created_at" timestamptz NOT NULL DEFAULT now()
+);
+GRANT SELECT ON auth."verification_keys" TO PUBLIC;
+-- the keys are data, which pg_dump must include
+SELECT pg_catalog.pg_extension_config_dump('auth.verification_keys', '');
+
+CREATE TRIGGER "verification_keys_changed"
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON auth."verification_keys"
+FOR EACH STATEMENT EXECUTE FUNCTION auth."verification_keys_changed"();
+"#,
+        name = "verification_keys",
+        requires = [verification_keys_changed],
+    );
+
+    /// Set the public keys for this postgres session.
+    #[pg_extern]
+    pub fn init() -> Result<(), ErrorReport> {
+        verification_keys()?;
+        Ok(())
+    }
+
+    /// Why a JWT was rejected.
+    #[derive(Clone)]
+    struct Rejection {
+        errcode: PgSqlErrorCode,
+        message: &'static str,
+        detail: Option<String>,
+    }
+
+    impl Rejection {
+        fn new(errcode: impl Into<PgSqlErrorCode>, message: &'static str) -> Self {
+            Self {
+                errcode: errcode.into(),
+                message,
+                detail: None,
             }
-        });
+        }
+    }
 
-        jti
+    /// The error raised once the `#[pg_extern]` function returns, after the
+    /// values owned by Rust are dropped.
+    impl From<Rejection> for ErrorReport {
+        fn from(rejection: Rejection) -> Self {
+            match rejection.detail {
+                Some(detail) => jwt_report!(rejection.errcode, rejection.message, detail),
+                None => jwt_report!(rejection.errcode, rejection.message),
+            }
+        }
     }
 
-    fn verify_time(payload: &Object) {
+    /// Audit and count the rejection of a JWT, before it's raised.
+    fn reject(token: &UnverifiedJwt, rejection: Rejection) -> Rejection {
+        audit::failed_jwt(token, rejection.message);
+        stats::failure(rejection.errcode as i32, rejection.message);
+        rejection
+    }
+
+    /// A JWT split into its parts, which are decoded before verifying the
+    /// signature so that failures can be audited.
+    struct DecodedJwt<'a> {
+        body: &'a str,
+        sig: &'a str,
+        /// A malformed header isn't an error, as we don't rely on it.
+        header: Option<Object>,
+        payload: Result<Object, Rejection>,
+    }
+
+    impl DecodedJwt<'_> {
+        fn unverified<'a>(&'a self, keys: &'a [Jwk]) -> UnverifiedJwt<'a> {
+            UnverifiedJwt {
+                header: self.header.as_ref(),
+                payload: self.payload.as_ref().ok(),
+                key_thumbprint: named_key(keys, self.header.as_ref()).map(|key| &*key.thumbprint),
+            }
+        }
+    }
+
+    /// The first key named by the `kid` of the header.
+    fn named_key<'a>(keys: &'a [Jwk], header: Option<&Object>) -> Option<&'a Jwk> {
+        keys.iter().find(|key| key.matches(header))
+    }
+
+    /// Split a JWT into its signed body and its signature, and the body into
+    /// the encoded header and payload.
+    fn split_jwt(jwt: &str) -> Result<(&str, &str, &str, &str), Rejection> {
+        let invalid_encoding = || Rejection::new(JwtErrorCode::InvalidJwt, "invalid JWT encoding");
+        let (body, sig) = jwt.rsplit_once('.').ok_or_else(invalid_encoding)?;
+        let (header, payload) = body.split_once('.').ok_or_else(invalid_encoding)?;
+        Ok((body, header, payload, sig))
+    }
+
+    fn decode_jwt(jwt: &str) -> Result<DecodedJwt, Rejection> {
+        let (body, header, payload, sig) = split_jwt(jwt)?;
+
+        Ok(DecodedJwt {
+            body,
+            sig,
+            header: try_json_base64_decode(header).ok(),
+            payload: try_json_base64_decode(payload).map_err(|(message, e)| Rejection {
+                errcode: JwtErrorCode::InvalidJwt.into(),
+                message,
+                detail: Some(e),
+            }),
+        })
+    }
+
+    /// Verify the JWT against the keys, without looking at the session.
+    /// Returns the token ID, and the key which verified it.
+    fn verify_jwt<'a>(keys: &'a [Jwk], decoded: &DecodedJwt) -> Result<(i64, &'a Jwk), Rejection> {
+        let key = select_key(keys, decoded)?;
+
+        let payload = decoded.payload.as_ref().map_err(Clone::clone)?;
+        let jti = payload.get("jti").and_then(|x| x.as_i64()).ok_or_else(|| {
+            Rejection::new(
+                JwtErrorCode::InvalidJwt,
+                "JWT payload must contain a valid 'jti' (JWT ID)",
+            )
+        })?;
+        verify_time(payload)?;
+        verify_issuer(decoded.header.as_ref(), payload)?;
+
+        Ok((jti, key))
+    }
+
+    /// The key which signed the JWT, among the keys named by its `kid`. When
+    /// none of them verifies it, the rejection of the first one is returned.
+    fn select_key<'a>(keys: &'a [Jwk], decoded: &DecodedJwt) -> Result<&'a Jwk, Rejection> {
+        let header = decoded.header.as_ref();
+        let mut rejection = None;
+        for key in keys.iter().filter(|key| key.matches(header)) {
+            match verify_key(key, header)
+                .and_then(|()| verify_signature(&key.verifying_key, decoded.body, decoded.sig))
+            {
+                Ok(()) => return Ok(key),
+                Err(e) => {
+                    rejection.get_or_insert(e);
+                }
+            }
+        }
+        Err(rejection.unwrap_or_else(|| {
+            Rejection::new(
+                JwtErrorCode::InvalidSignature,
+                "JWT 'kid' does not match the JWK",
+            )
+        }))
+    }
+
+    /// Check that the key may verify a JWT with this header, now.
+    fn verify_key(key: &Jwk, header: Option<&Object>) -> Result<(), Rejection> {
+        if let Some(alg) = &key.alg {
+            let header_alg = header.and_then(|header| header.get("alg"));
+            if header_alg.and_then(|alg| alg.as_str()) != Some(alg.as_str()) {
+                return Err(Rejection::new(
+                    JwtErrorCode::InvalidSignature,
+                    "JWT 'alg' does not match the JWK",
+                ));
+            }
+        }
+
+        let now = epoch();
+        if key.nbf.is_some_and(|nbf| now < nbf as f64) {
+            return Err(Rejection::new(
+                JwtErrorCode::InvalidJwk,
+                "JWK used before it is ready",
+            ));
+        }
+        if key.exp.is_some_and(|exp| exp as f64 <= now) {
+            return Err(Rejection::new(
+                JwtErrorCode::InvalidJwk,
+                "JWK used after it has expired",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check the JWT against the issuer of `pg_session_jwt.oidc_discovery`.
+    fn verify_issuer(header: Option<&Object>, payload: &Object) -> Result<(), Rejection> {
+        if worker::oidc_discovery().is_none() {
+            return Ok(());
+        }
+        let Some(oidc) = remote_jwks().oidc else {
+            return Err(Rejection::new(
+                JwtErrorCode::UntrustedIssuer,
+                "the OIDC discovery document is not loaded",
+            ));
+        };
+
+        let alg = header.and_then(|header| header.get("alg")?.as_str());
+        if !alg.is_some_and(|alg| {
+            oidc.id_token_signing_alg_values_supported
+                .iter()
+                .any(|a| a == alg)
+        }) {
+            return Err(Rejection::new(
+                JwtErrorCode::InvalidSignature,
+                "JWT 'alg' is not supported by the issuer",
+            ));
+        }
+        if payload.get("iss").and_then(|iss| iss.as_str()) != Some(oidc.issuer.as_str()) {
+            return Err(Rejection::new(
+                JwtErrorCode::UntrustedIssuer,
+                "JWT 'iss' does not match the issuer",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn verify_signature(key: &VerifyingKey, body: &str, sig: &str) -> Result<(), Rejection> {
+        let mut sig_bytes = [0; 64];
+        Base64UrlUnpadded::decode(sig, &mut sig_bytes).map_err(|_| {
+            Rejection::new(JwtErrorCode::InvalidJwt, "invalid JWT signature encoding")
+        })?;
+        let sig = Signature::from_bytes(&sig_bytes);
+
+        key.verify_strict(body.as_bytes(), &sig)
+            .map_err(|_| Rejection::new(JwtErrorCode::InvalidSignature, "invalid JWT signature"))
+    }
+
+    fn verify_token_id(jti: i64) -> Result<(), Rejection> {
+        if JTI.with_borrow(|t| jti <= *t) {
+            return Err(Rejection::new(
+                JwtErrorCode::Replayed,
+                "Token ID must be strictly monotonically increasing.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn verify_time(payload: &Object) -> Result<(), Rejection> {
         let now = now()
             .to_utc()
             .extract_part(DateTimeParts::Epoch)
-            .unwrap_or_else(|| {
-                error_code!(
+            .ok_or_else(|| {
+                Rejection::new(
                     PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
                     "could not get current unix epoch",
                 )
-            });
+            })?;
         if let Some(nbf) = payload.get("nbf") {
-            let nbf = nbf.as_i64().unwrap_or_else(|| {
-                error_code!(
-                    PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
+            let nbf = nbf.as_i64().ok_or_else(|| {
+                Rejection::new(
+                    JwtErrorCode::InvalidJwt,
                     "'nbf' (Not Before) must be an integer representing seconds since unix epoch",
                 )
-            });
+            })?;
             let nbf = AnyNumeric::from(nbf);
 
             if now < nbf {
-                error_code!(
-                    PgSqlErrorCode::ERRCODE_CHECK_VIOLATION,
+                return Err(Rejection::new(
+                    JwtErrorCode::NotYetValid,
                     "Token used before it is ready",
-                )
+                ));
             }
         }
         if let Some(exp) = payload.get("exp") {
-            let exp = exp.as_i64().unwrap_or_else(|| {
-                error_code!(
-                    PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
+            let exp = exp.as_i64().ok_or_else(|| {
+                Rejection::new(
+                    JwtErrorCode::InvalidJwt,
                     "'exp' (Expiration) must be an integer representing seconds since unix epoch",
                 )
-            });
+            })?;
             let exp = AnyNumeric::from(exp);
 
             if exp < now {
-                error_code!(
-                    PgSqlErrorCode::ERRCODE_CHECK_VIOLATION,
+                return Err(Rejection::new(
+                    JwtErrorCode::Expired,
                     "Token used after it has expired",
-                )
+                ));
             }
         }
+
+        Ok(())
     }
 
     /// Decrypt the JWT and store it.
@@ -204,149 +900,781 @@ pub mod auth {
     ///
     /// This function will panic if the JWT could not be verified.
     #[pg_extern]
-    pub fn jwt_session_init(jwt: &str) {
-        Spi::run(
-            format!(
-                "SET {} = {}",
-                NEON_AUTH_JWT_RUNTIME_PARAM,
-                spi::quote_literal(jwt)
-            )
-            .as_str(),
-        )
+    pub fn jwt_session_init(jwt: &str) -> Result<(), ErrorReport> {
+        set_jwt_guc(Some(jwt), false);
+        validate_jwt()?;
+
+        // a session-level SET survives the end of a transaction which called
+        // jwt_transaction_init, so this is what we must go back to.
+        if XACT_JWT.with_borrow(Option::is_some) {
+            crate::xact::save_session_state();
+            XACT_JWT.replace(Some(JWT.with_borrow(Clone::clone)));
+        }
+        Ok(())
+    }
+
+    /// Decrypt the JWT and store it until the end of the current transaction.
+    ///
+    /// The JWT is set with `SET LOCAL` semantics, so both the runtime parameter
+    /// and the cached payload are reverted once the transaction ends.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the JWT could not be verified.
+    #[pg_extern]
+    pub fn jwt_transaction_init(jwt: &str) -> Result<(), ErrorReport> {
+        if XACT_JWT.with_borrow(Option::is_none) {
+            crate::xact::save_session_state();
+            XACT_JWT.replace(Some(JWT.with_borrow(Clone::clone)));
+        }
+
+        set_jwt_guc(Some(jwt), true);
+        validate_jwt()?;
+        Ok(())
+    }
+
+    /// Forget the JWT of this postgres session.
+    ///
+    /// Resets the runtime parameter, the cached payload and the token ID
+    /// counter so that the connection can be handed to another user.
+    #[pg_extern]
+    pub fn jwt_session_reset() {
+        set_jwt_guc(None, false);
+        reset_session_state();
+    }
+
+    /// Verify a JWT against the JWK, without changing the session.
+    ///
+    /// Unlike `jwt_session_init`, an invalid JWT doesn't raise an error, and
+    /// its `jti` isn't compared with the one of the session.
+    #[pg_extern(stable, name = "validate_jwt")]
+    pub fn check_jwt(
+        jwt: &str,
+    ) -> Result<
+        TableIterator<
+            'static,
+            (
+                name!(valid, bool),
+                name!(reason, Option<String>),
+                name!(header, Option<JsonB>),
+                name!(payload, Option<JsonB>),
+            ),
+        >,
+        ErrorReport,
+    > {
+        let keys = verification_keys()?;
+        let verified = decode_jwt(jwt).and_then(|decoded| {
+            verify_jwt(&keys, &decoded)?;
+            Ok(decoded)
+        });
+
+        Ok(TableIterator::once(match verified {
+            Ok(decoded) => (
+                true,
+                None,
+                Some(JsonB(serde_json::Value::Object(
+                    decoded.header.unwrap_or_default(),
+                ))),
+                Some(JsonB(serde_json::Value::Object(
+                    decoded.payload.unwrap_or_default(),
+                ))),
+            ),
+            Err(rejection) => (false, Some(rejection.message.to_string()), None, None),
+        }))
+    }
+
+    /// Decode the header of a JWT, WITHOUT verifying it.
+    ///
+    /// Only meant to look into a JWT when debugging, never for authorization.
+    #[pg_extern(immutable, parallel_safe)]
+    pub fn jwt_decode_header(jwt: &str) -> JsonB {
+        decode_unverified(jwt, |(_, header, _, _)| header)
+    }
+
+    /// Decode the payload of a JWT, WITHOUT verifying it.
+    ///
+    /// Only meant to look into a JWT when debugging, never for authorization.
+    #[pg_extern(immutable, parallel_safe)]
+    pub fn jwt_decode_payload(jwt: &str) -> JsonB {
+        decode_unverified(jwt, |(_, _, payload, _)| payload)
+    }
+
+    /// Decode a part of the JWT, the same way validation does.
+    fn decode_unverified<'a>(
+        jwt: &'a str,
+        part: impl FnOnce((&'a str, &'a str, &'a str, &'a str)) -> &'a str,
+    ) -> JsonB {
+        let encoded = split_jwt(jwt)
+            .map(part)
+            .unwrap_or_else(|rejection| jwt_error!(rejection.errcode, rejection.message));
+        let decoded: Object = try_json_base64_decode(encoded)
+            .unwrap_or_else(|(message, e)| jwt_error!(JwtErrorCode::InvalidJwt, message, e));
+        JsonB(serde_json::Value::Object(decoded))
+    }
+
+    /// The public keys which verify JWTs, as a JWK Set: the ones from
+    /// `pg_session_jwt.jwk`, `pg_session_jwt.jwks_file`,
+    /// `auth.verification_keys` and the remote JWKS, and the pending and active
+    /// keys of `auth.signing_keys`. Keys past their `exp` are left out.
+    #[pg_extern(stable, security_definer)]
+    #[search_path(pg_catalog, pg_temp)]
+    pub fn jwks() -> JsonB {
+        let mut keys = configured_keys();
+        for key in stored_jwks() {
+            if !keys.iter().any(|k| k.verifying_key == key.verifying_key) {
+                keys.push(key);
+            }
+        }
+
+        let now = epoch();
+        let keys: Vec<_> = keys
+            .iter()
+            .filter(|key| !key.exp.is_some_and(|exp| exp as f64 <= now))
+            .map(Jwk::to_json)
+            .collect();
+        JsonB(serde_json::json!({ "keys": keys }))
+    }
+
+    /// The public half of the pending and active keys of `auth.signing_keys`.
+    fn stored_jwks() -> Vec<Jwk> {
+        let rows = Spi::connect(|client| {
+            client
+                .select(
+                    "SELECT kid, jwk FROM auth.signing_keys \
+                     WHERE status IN ('pending', 'active') ORDER BY created_at",
+                    None,
+                    None,
+                )?
+                .map(|row| Ok((row.get_by_name("kid")?, row.get_by_name("jwk")?)))
+                .collect::<Result<Vec<(Option<String>, Option<JsonB>)>, pgrx::spi::Error>>()
+        })
         .unwrap_or_else(|e| {
             error_code!(
-                PgSqlErrorCode::ERRCODE_S_R_E_PROHIBITED_SQL_STATEMENT_ATTEMPTED,
-                format!("Couldn't set {}", NEON_AUTH_JWT_RUNTIME_PARAM),
+                PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+                "could not read auth.signing_keys",
                 e.to_string(),
             )
         });
-        validate_jwt();
+
+        rows.into_iter()
+            .map(|(kid, jwk)| {
+                let jwk = jwk.map_or(serde_json::Value::Null, |jwk| jwk.0);
+                let key: Ed25519OkpPrivate = serde_json::from_value(jwk).unwrap_or_else(|e| {
+                    jwt_error!(
+                        JwtErrorCode::InvalidJwk,
+                        "auth.signing_keys requires Ed25519 private JWKs",
+                        format!("key {}: {e}", kid.as_deref().unwrap_or_default()),
+                    )
+                });
+                Jwk::new(SigningKey::from_bytes(&key.d).verifying_key(), kid)
+            })
+            .collect()
     }
 
-    fn get_jwt_guc() -> Option<&'static str> {
-        Some(NEON_AUTH_JWT.get()?.to_str().unwrap_or_else(|e| {
-            error_code!(
-                PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                format!("invalid JWT parameter {}", NEON_AUTH_JWT_RUNTIME_PARAM),
+    /// Sign the payload with a private Ed25519 JWK, into a compact JWS which
+    /// `auth.jwt_session_init()` accepts.
+    ///
+    /// Only superusers can call it, unless they grant it to other roles.
+    #[pg_extern]
+    pub fn jwt_sign(payload: JsonB, key: JsonB) -> String {
+        let key: Ed25519OkpPrivate = serde_json::from_value(key.0).unwrap_or_else(|e| {
+            jwt_error!(
+                JwtErrorCode::InvalidJwk,
+                "auth.jwt_sign requires an Ed25519 private JWK",
                 e.to_string(),
             )
-        }))
+        });
+        let signing_key = SigningKey::from_bytes(&key.d);
+        if signing_key.verifying_key().as_bytes() != &*key.x {
+            jwt_error!(
+                JwtErrorCode::InvalidJwk,
+                "the public key of the JWK doesn't match its private key",
+            );
+        }
+
+        let serde_json::Value::Object(payload) = payload.0 else {
+            jwt_error!(
+                JwtErrorCode::InvalidJwt,
+                "JWT payload must be a JSON object"
+            );
+        };
+        let mut header = Object::new();
+        header.insert("alg".into(), "EdDSA".into());
+        header.insert("typ".into(), "JWT".into());
+        // keys without a kid are named by their thumbprint, like in auth.jwks()
+        let kid = key
+            .kid
+            .unwrap_or_else(|| thumbprint(&signing_key.verifying_key()));
+        header.insert("kid".into(), kid.into());
+
+        let body = format!(
+            "{}.{}",
+            json_base64_encode(header),
+            json_base64_encode(payload)
+        );
+        let sig = signing_key.sign(body.as_bytes());
+        format!(
+            "{body}.{}",
+            Base64UrlUnpadded::encode_string(&sig.to_bytes())
+        )
     }
 
-    fn validate_jwt() -> Option<serde_json::Map<String, serde_json::Value>> {
-        let jwt = get_jwt_guc()?;
-        let key = get_jwk_guc();
+    pgrx::extension_sql!(
+        r#"
+REVOKE ALL ON FUNCTION auth."jwt_sign"(jsonb, jsonb) FROM PUBLIC;
+"#,
+        name = "jwt_sign_privileges",
+        requires = [jwt_sign],
+    );
 
-        JWT.with_borrow_mut(|cached_jwt| {
-            match cached_jwt {
-                Some((cached_jwt, payload)) if cached_jwt == jwt => {
-                    log_audit_validated_jwt(payload);
-                    Some(payload.clone())
-                }
-                _ => {
-                    let (body, sig) = jwt.rsplit_once('.').unwrap_or_else(|| {
-                        error_code!(
-                            PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                            "invalid JWT encoding",
-                        )
-                    });
-                    let (_, payload) = body.split_once('.').unwrap_or_else(|| {
-                        error_code!(
-                            PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                            "invalid JWT encoding",
-                        )
-                    });
+    pgrx::extension_sql!(
+        r#"
+-- private JWKs used by auth.issue_token(), only readable by superusers
+CREATE TABLE auth."signing_keys" (
+    "kid" TEXT PRIMARY KEY,
+    "jwk" jsonb NOT NULL,
+    "status" TEXT NOT NULL DEFAULT 'pending'
+        CHECK ("status" IN ('pending', 'active', 'retired')),
+    "created_at" timestamptz NOT NULL DEFAULT now(),
+    "activated_at" timestamptz,
+    "retired_at" timestamptz
+);
+CREATE UNIQUE INDEX "signing_keys_one_active" ON auth."signing_keys" ((true))
+    WHERE "status" = 'active';
+REVOKE ALL ON auth."signing_keys" FROM PUBLIC;
+-- the keys are data, which pg_dump must include
+SELECT pg_catalog.pg_extension_config_dump('auth.signing_keys', '');
 
-                    verify_signature(&key, body, sig);
+-- strictly increasing, as required by auth.jwt_session_init()
+CREATE SEQUENCE auth."token_id_seq";
+REVOKE ALL ON SEQUENCE auth."token_id_seq" FROM PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('auth.token_id_seq', '');
 
-                    let payload: Object = json_base64_decode(payload);
-                    let jti = verify_token_id(&payload);
-                    verify_time(&payload);
+CREATE FUNCTION auth."issue_token"(
+    "sub" TEXT,
+    "claims" jsonb DEFAULT '{}',
+    "ttl" interval DEFAULT '1 hour'
+) RETURNS TEXT
+VOLATILE SECURITY DEFINER
+SET search_path = pg_catalog, pg_temp
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    signing_key auth.signing_keys;
+    issued_at bigint := floor(extract(epoch FROM now()));
+BEGIN
+    SELECT * INTO signing_key FROM auth.signing_keys WHERE status = 'active';
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'no active key in auth.signing_keys' USING ERRCODE = 'invalid_parameter_value';
+    END IF;
 
-                    // update state
-                    JTI.replace(jti);
-                    *cached_jwt = Some((jwt.to_string(), payload.clone()));
-                    log_audit_validated_jwt(&payload);
-                    Some(payload)
-                }
+    RETURN auth.jwt_sign(
+        coalesce(issue_token.claims, '{}') || jsonb_build_object(
+            'sub', issue_token.sub,
+            'iat', issued_at,
+            'exp', issued_at + floor(extract(epoch FROM issue_token.ttl)),
+            'jti', nextval('auth.token_id_seq')
+        ),
+        signing_key.jwk || jsonb_build_object('kid', signing_key.kid)
+    );
+END
+$$;
+REVOKE ALL ON FUNCTION auth."issue_token"(TEXT, jsonb, interval) FROM PUBLIC;
+"#,
+        name = "signing_keys",
+        requires = [jwt_sign],
+    );
+
+    /// State derived from `pg_session_jwt.jwt`, which must be rolled back
+    /// together with it.
+    #[derive(Clone, Default)]
+    pub(crate) struct SessionState {
+        jwt: Option<Rc<ValidatedJwt>>,
+        jti: i64,
+        xact_jwt: Option<Option<Rc<ValidatedJwt>>>,
+    }
+
+    pub(crate) fn session_state() -> SessionState {
+        SessionState {
+            jwt: JWT.with_borrow(Clone::clone),
+            jti: JTI.with_borrow(Clone::clone),
+            xact_jwt: XACT_JWT.with_borrow(Clone::clone),
+        }
+    }
+
+    pub(crate) fn restore_session_state(state: SessionState) {
+        publish_session(state.jwt.as_deref());
+        JWT.replace(state.jwt);
+        JTI.replace(state.jti);
+        XACT_JWT.replace(state.xact_jwt);
+    }
+
+    /// Drop all the state derived from the JWT of this postgres session.
+    pub(crate) fn reset_session_state() {
+        crate::xact::save_session_state();
+        restore_session_state(SessionState::default());
+    }
+
+    /// Go back to the session-level JWT once the transaction which called
+    /// `jwt_transaction_init` commits.
+    pub(crate) fn end_transaction_scope() {
+        if let Some(saved) = XACT_JWT.take() {
+            publish_session(saved.as_deref());
+            JWT.replace(saved);
+        }
+    }
+
+    /// Publish the JWT this backend is acting for in `auth.active_sessions`.
+    fn publish_session(jwt: Option<&ValidatedJwt>) {
+        sessions::publish(jwt.map(|jwt| {
+            ActiveSession {
+                sub: jwt.payload.get("sub").and_then(|sub| sub.as_str()),
+                jti: jwt
+                    .payload
+                    .get("jti")
+                    .and_then(|jti| jti.as_i64())
+                    .unwrap_or_default(),
+                exp: jwt.payload.get("exp").and_then(|exp| exp.as_i64()),
+                validated_at: jwt.validated_at,
             }
+        }));
+    }
+
+    /// Set (or reset, when `jwt` is `None`) the JWT runtime parameter.
+    ///
+    /// This is what `SET` does, without parsing and planning a statement
+    /// through SPI. Postgres still refuses to change the parameter inside a
+    /// security-restricted operation as it's flagged `NOT_WHILE_SEC_REST`.
+    fn set_jwt_guc(jwt: Option<&str>, is_local: bool) {
+        let name = CString::new(NEON_AUTH_JWT_RUNTIME_PARAM).unwrap();
+        let value = jwt.map(|jwt| {
+            CString::new(jwt).unwrap_or_else(|e| {
+                jwt_error!(
+                    JwtErrorCode::InvalidJwt,
+                    format!("invalid JWT parameter {}", NEON_AUTH_JWT_RUNTIME_PARAM),
+                    e.to_string(),
+                )
+            })
+        });
+        let action = if is_local {
+            pg_sys::GucAction::GUC_ACTION_LOCAL
+        } else {
+            pg_sys::GucAction::GUC_ACTION_SET
+        };
+
+        unsafe {
+            pg_sys::set_config_option(
+                name.as_ptr(),
+                value.as_deref().map_or(std::ptr::null(), CStr::as_ptr),
+                pg_sys::GucContext::PGC_USERSET,
+                pg_sys::GucSource::PGC_S_SESSION,
+                action,
+                true,
+                pg_sys::ERROR as _,
+                false,
+            );
+        }
+    }
+
+    fn get_jwt_guc() -> Result<Option<&'static str>, Rejection> {
+        let Some(jwt) = NEON_AUTH_JWT.get() else {
+            return Ok(None);
+        };
+        jwt.to_str().map(Some).map_err(|e| Rejection {
+            errcode: JwtErrorCode::InvalidJwt.into(),
+            message: "invalid JWT parameter pg_session_jwt.jwt",
+            detail: Some(e.to_string()),
         })
     }
 
-    fn log_audit_validated_jwt(payload: &Object) {
-        log!(
-            "JWT issued for sub={} and aud={} was succesfully validated",
-            payload.get("sub").unwrap_or(&"".into()),
-            payload.get("aud").unwrap_or(&"".into())
-        );
+    /// The JWT which was already validated, if it's still the current one.
+    fn cached_jwt(jwt: &str) -> Option<Rc<ValidatedJwt>> {
+        JWT.with_borrow(|cached_jwt| {
+            cached_jwt
+                .as_ref()
+                .filter(|cached_jwt| cached_jwt.jwt == jwt)
+                .cloned()
+        })
     }
 
-    fn log_audit_guc_claims(claims: Option<&Object>) {
-        match claims {
-            Some(payload) => log!(
-                "JWT claims from GUC variable: sub={} and aud={}",
-                payload.get("sub").unwrap_or(&"".into()),
-                payload.get("aud").unwrap_or(&"".into())
-            ),
-            None => log!("No JWT claims found in GUC variable"),
+    /// The current JWT, only if it was already validated. Unlike
+    /// `validate_jwt`, reading it isn't audited nor counted.
+    fn validated_jwt() -> Option<Rc<ValidatedJwt>> {
+        get_jwt_guc().ok().flatten().and_then(cached_jwt)
+    }
+
+    fn validate_jwt() -> Result<Option<Rc<ValidatedJwt>>, Rejection> {
+        let jwt =
+            get_jwt_guc().map_err(|rejection| reject(&UnverifiedJwt::default(), rejection))?;
+        let Some(jwt) = jwt else {
+            return Ok(None);
+        };
+
+        if let Some(validated) = cached_jwt(jwt) {
+            audit::validated_jwt(
+                &validated.header,
+                &validated.payload,
+                &validated.key_thumbprint,
+                true,
+            );
+            stats::cache_hit();
+            return Ok(Some(validated));
         }
+
+        let keys = verification_keys()
+            .map_err(|rejection| reject(&UnverifiedJwt::default(), rejection))?;
+        let decoded = decode_jwt(jwt).map_err(|rejection| {
+            let token = UnverifiedJwt {
+                key_thumbprint: named_key(&keys, None).map(|key| &*key.thumbprint),
+                ..Default::default()
+            };
+            reject(&token, rejection)
+        })?;
+        let (jti, key) = verify_jwt(&keys, &decoded)
+            .and_then(|(jti, key)| verify_token_id(jti).map(|_| (jti, key)))
+            .map_err(|rejection| reject(&decoded.unverified(&keys), rejection))?;
+
+        let header = decoded.header.unwrap_or_default();
+        let payload = decoded.payload.unwrap_or_default();
+
+        // update state
+        let validated = Rc::new(ValidatedJwt::new(jwt, header, payload, key));
+        crate::xact::save_session_state();
+        JTI.replace(jti);
+        JWT.replace(Some(validated.clone()));
+        publish_session(Some(&validated));
+        audit::validated_jwt(
+            &validated.header,
+            &validated.payload,
+            &validated.key_thumbprint,
+            false,
+        );
+        stats::validation();
+        Ok(Some(validated))
     }
 
-    fn get_claims_from_guc() -> Option<serde_json::Value> {
-        let claims: Option<serde_json::Value> = Spi::get_one::<String>("SELECT current_setting('request.jwt.claims', true)")
-            .ok()
-            .flatten()
-            .filter(|s| !s.is_empty())
-            .and_then(|claims| serde_json::from_str(&claims).ok());
-        
-        log_audit_guc_claims(claims.as_ref().and_then(|v| v.as_object()));
+    fn get_claims_from_guc() -> Option<Rc<serde_json::Value>> {
+        let (claims, from_cache) = claims_from_guc();
+        audit::guc_claims(claims.as_ref().and_then(|v| v.as_object()), from_cache);
+        stats::fallback_read();
         claims
     }
 
+    /// The claims from `request.jwt.claims`, and whether they were cached.
+    ///
+    /// Unlike `get_claims_from_guc`, reading them isn't audited nor counted.
+    fn claims_from_guc() -> (Option<Rc<serde_json::Value>>, bool) {
+        let raw: &[u8] = unsafe {
+            let raw =
+                pg_sys::GetConfigOption(POSTGREST_JWT_CLAIMS_RUNTIME_PARAM.as_ptr(), true, false);
+            if raw.is_null() {
+                &[]
+            } else {
+                CStr::from_ptr(raw).to_bytes()
+            }
+        };
+
+        // only parse the claims again once they change
+        let (claims, from_cache) = CLAIMS.with_borrow_mut(|cached_claims| match cached_claims {
+            Some((cached_raw, claims)) if cached_raw.as_slice() == raw => (claims.clone(), true),
+            _ => {
+                let claims: Option<Rc<serde_json::Value>> = Some(raw)
+                    .filter(|raw| !raw.is_empty())
+                    .and_then(|raw| serde_json::from_slice(raw).ok())
+                    .map(Rc::new);
+                *cached_claims = Some((raw.to_vec(), claims.clone()));
+                (claims, false)
+            }
+        });
+
+        (claims, from_cache)
+    }
+
     /// Extract a value from the shared state.
     #[pg_extern(parallel_safe, stable)]
-    pub fn session() -> JsonB {
+    pub fn session() -> Result<JsonbDatum, ErrorReport> {
         // If the JWK is not defined, we fallback to the request.jwt.claims GUC
         // https://docs.postgrest.org/en/v12/references/transactions.html#request-headers-cookies-and-jwt-claims
-        if NEON_AUTH_JWK.get().is_none() {
-            return JsonB(get_claims_from_guc().unwrap_or(serde_json::Value::Null));
+        if !jwk_mode() {
+            // the jsonb is built from the claims anyway, which costs more than the copy
+            let claims = get_claims_from_guc().map_or(serde_json::Value::Null, |claims| {
+                serde_json::Value::clone(&claims)
+            });
+            return Ok(JsonB(claims).into());
         }
-        JsonB(validate_jwt().map_or(serde_json::Value::Null, serde_json::Value::Object))
+        Ok(match validate_jwt()? {
+            Some(validated) => validated.jsonb.get(),
+            None => JsonB(serde_json::Value::Null).into(),
+        })
     }
 
     #[pg_extern(parallel_safe, stable)]
-    pub fn user_id() -> Option<String> {
+    pub fn user_id() -> Result<Option<String>, ErrorReport> {
         // https://docs.postgrest.org/en/v12/references/transactions.html#request-headers-cookies-and-jwt-claims
-        if NEON_AUTH_JWK.get().is_none() {
+        if !jwk_mode() {
             // Get subject from the claims JSONB
-            return get_claims_from_guc()
-                .and_then(|json| json.get("sub").cloned())
-                .and_then(|s| s.as_str().map(|s| s.to_owned()));
+            return Ok(get_claims_from_guc()
+                .and_then(|json| json.get("sub")?.as_str().map(|s| s.to_owned())));
         }
 
-        match validate_jwt()?.get("sub")? {
-            serde_json::Value::String(s) => Some(s.clone()),
-            _ => error_code!(
-                PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                "invalid subject claim in the JWT"
-            ),
+        let Some(validated) = validate_jwt()? else {
+            return Ok(None);
+        };
+        match validated.payload.get("sub") {
+            Some(serde_json::Value::String(s)) => Ok(Some(s.clone())),
+            None => Ok(None),
+            Some(_) => {
+                let token = UnverifiedJwt {
+                    header: Some(&validated.header),
+                    payload: Some(&validated.payload),
+                    key_thumbprint: Some(&*validated.key_thumbprint),
+                };
+                let rejection =
+                    Rejection::new(JwtErrorCode::InvalidJwt, "invalid subject claim in the JWT");
+                Err(reject(&token, rejection).into())
+            }
         }
     }
 
-    fn json_base64_decode<D: DeserializeOwned>(s: &str) -> D {
-        let r = Decoder::<Base64UrlUnpadded>::new(s.as_bytes()).unwrap_or_else(|e| {
-            error_code!(
-                PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                "could not decode JWT component",
+    /// Describe how this backend authenticates the session, for debugging.
+    ///
+    /// It only reads what was already validated: calling it never validates
+    /// the JWT, nor audits or counts anything.
+    #[pg_extern(volatile)]
+    pub fn session_info() -> TableIterator<
+        'static,
+        (
+            name!(mode, String),
+            name!(key_id, Option<String>),
+            name!(key_thumbprint, Option<String>),
+            name!(jti, Option<i64>),
+            name!(iat, Option<TimestampWithTimeZone>),
+            name!(exp, Option<TimestampWithTimeZone>),
+            name!(expires_in, Option<f64>),
+            name!(validated_at, Option<TimestampWithTimeZone>),
+            name!(from_cache, bool),
+        ),
+    > {
+        let timestamp = |payload: &Object, claim: &str| {
+            payload
+                .get(claim)
+                .and_then(|value| value.as_f64())
+                .map(to_timestamp)
+        };
+        let expires_in = |payload: &Object| {
+            payload
+                .get("exp")
+                .and_then(|exp| exp.as_f64())
+                .map(|exp| exp - epoch())
+        };
+
+        if !jwk_mode() {
+            let (claims, from_cache) = claims_from_guc();
+            let Some(claims) = claims.as_ref().and_then(|claims| claims.as_object()) else {
+                return TableIterator::once((
+                    "none".to_string(),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    from_cache,
+                ));
+            };
+            return TableIterator::once((
+                "fallback".to_string(),
+                None,
+                None,
+                claims.get("jti").and_then(|jti| jti.as_i64()),
+                timestamp(claims, "iat"),
+                timestamp(claims, "exp"),
+                expires_in(claims),
+                None,
+                from_cache,
+            ));
+        }
+
+        // the JWT is only described once it was validated, by
+        // auth.jwt_session_init() or the first access to the session
+        let validated = validated_jwt();
+        let validated = validated.as_deref();
+        TableIterator::once((
+            "jwk".to_string(),
+            validated
+                .and_then(|v| v.header.get("kid"))
+                .map(|kid| match kid {
+                    serde_json::Value::String(kid) => kid.clone(),
+                    kid => kid.to_string(),
+                }),
+            validated.map(|v| v.key_thumbprint.clone()),
+            validated.and_then(|v| v.payload.get("jti").and_then(|jti| jti.as_i64())),
+            validated.and_then(|v| timestamp(&v.payload, "iat")),
+            validated.and_then(|v| timestamp(&v.payload, "exp")),
+            validated.and_then(|v| expires_in(&v.payload)),
+            validated.map(|v| to_timestamp(v.validated_at)),
+            validated.is_some(),
+        ))
+    }
+
+    /// The JWK thumbprint of a public or private Ed25519 JWK, as defined in
+    /// [RFC 7638].
+    ///
+    /// [RFC 7638]: https://www.rfc-editor.org/rfc/rfc7638
+    #[pg_extern(immutable, parallel_safe)]
+    pub fn jwk_thumbprint(jwk: JsonB) -> String {
+        let mut jwk = jwk.0;
+        // the thumbprint of a private key is the one of its public key
+        if let Some(jwk) = jwk.as_object_mut() {
+            jwk.remove("d");
+        }
+        let jwk: Ed25519Okp = serde_json::from_value(jwk).unwrap_or_else(|e| {
+            jwt_error!(
+                JwtErrorCode::InvalidJwk,
+                "auth.jwk_thumbprint requires an Ed25519 JWK",
                 e.to_string(),
             )
         });
-        serde_json::from_reader(r).unwrap_or_else(|e| {
-            error_code!(
-                PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
-                "could not parse JWT component",
+        let key = VerifyingKey::from_bytes(&jwk.x).unwrap_or_else(|e| {
+            jwt_error!(
+                JwtErrorCode::InvalidJwk,
+                "auth.jwk_thumbprint requires an Ed25519 JWK",
                 e.to_string(),
             )
+        });
+        thumbprint(&key)
+    }
+
+    /// The JWK thumbprint of the key, as defined in [RFC 7638].
+    ///
+    /// [RFC 7638]: https://www.rfc-editor.org/rfc/rfc7638
+    fn thumbprint(key: &VerifyingKey) -> String {
+        // the required members, in lexicographic order and without whitespace
+        let jwk = format!(
+            r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#,
+            Base64UrlUnpadded::encode_string(key.as_bytes())
+        );
+        Base64UrlUnpadded::encode_string(&Sha256::digest(jwk.as_bytes()))
+    }
+
+    /// Current time, in seconds since the unix epoch.
+    fn epoch() -> f64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0.0, |elapsed| elapsed.as_secs_f64())
+    }
+
+    /// The validated JWT, if it's safe to fold its claims into plans.
+    fn foldable_jwt() -> Option<Rc<ValidatedJwt>> {
+        // request.jwt.claims can change without us noticing, unlike
+        // pg_session_jwt.jwt which invalidates the folded plans
+        if !jwk_mode() {
+            return None;
+        }
+        // planning (or EXPLAIN) must not validate the JWT: one which isn't
+        // validated yet is left to the function, when the query runs
+        validated_jwt()
+    }
+
+    /// Planner support for `auth.session()`: fold it into a constant.
+    #[pg_extern]
+    pub fn session_support(request: Internal) -> Internal {
+        planner::simplify(request, |fcall| {
+            let validated = foldable_jwt()?;
+            planner::make_const(fcall, validated.jsonb.get().into_datum())
         })
     }
+
+    /// Planner support for `auth.user_id()`: fold it into a constant.
+    #[pg_extern]
+    pub fn user_id_support(request: Internal) -> Internal {
+        planner::simplify(request, |fcall| {
+            match foldable_jwt()?.payload.get("sub") {
+                Some(serde_json::Value::String(sub)) => {
+                    planner::make_const(fcall, sub.as_str().into_datum())
+                }
+                None => planner::make_const(fcall, None),
+                // leave it to auth.user_id() to raise the error
+                Some(_) => None,
+            }
+        })
+    }
+
+    pgrx::extension_sql!(
+        r#"
+ALTER FUNCTION auth."session"() SUPPORT auth."session_support";
+ALTER FUNCTION auth."user_id"() SUPPORT auth."user_id_support";
+"#,
+        name = "planner_support",
+        requires = [session, user_id, session_support, user_id_support],
+    );
+
+    /// Validation counters, shared by all the sessions.
+    #[pg_extern(volatile)]
+    pub fn stats() -> TableIterator<
+        'static,
+        (
+            name!(counter, String),
+            name!(sqlstate, Option<String>),
+            name!(reason, Option<String>),
+            name!(count, i64),
+        ),
+    > {
+        TableIterator::new(stats::rows())
+    }
+
+    /// Reset the validation counters.
+    #[pg_extern(volatile)]
+    pub fn stats_reset() {
+        stats::reset()
+    }
+
+    /// The JWT each backend is acting for.
+    #[pg_extern(volatile)]
+    pub fn active_sessions() -> TableIterator<
+        'static,
+        (
+            name!(pid, i32),
+            name!(sub, Option<String>),
+            name!(jti, i64),
+            name!(exp, Option<TimestampWithTimeZone>),
+            name!(validated_at, TimestampWithTimeZone),
+        ),
+    > {
+        TableIterator::new(sessions::rows().into_iter().map(
+            |(pid, sub, jti, exp, validated_at)| {
+                (
+                    pid,
+                    sub,
+                    jti,
+                    exp.map(|exp| to_timestamp(exp as f64)),
+                    to_timestamp(validated_at),
+                )
+            },
+        ))
+    }
+
+    pgrx::extension_sql!(
+        r#"
+CREATE VIEW auth."stats" AS SELECT * FROM auth."stats"();
+GRANT SELECT ON auth."stats" TO PUBLIC;
+REVOKE ALL ON FUNCTION auth."stats_reset"() FROM PUBLIC;
+
+CREATE VIEW auth."active_sessions" AS SELECT * FROM auth."active_sessions"();
+GRANT SELECT ON auth."active_sessions" TO PUBLIC;
+"#,
+        name = "stats_views",
+        requires = [stats, stats_reset, active_sessions],
+    );
+
+    fn json_base64_encode(object: Object) -> String {
+        Base64UrlUnpadded::encode_string(serde_json::Value::Object(object).to_string().as_bytes())
+    }
+
+    fn try_json_base64_decode<D: DeserializeOwned>(s: &str) -> Result<D, (&'static str, String)> {
+        let r = Decoder::<Base64UrlUnpadded>::new(s.as_bytes())
+            .map_err(|e| ("could not decode JWT component", e.to_string()))?;
+        serde_json::from_reader(r).map_err(|e| ("could not parse JWT component", e.to_string()))
+    }
 }
@@ -0,0 +1,75 @@
+//! Planner support for `auth.user_id()` and `auth.session()`.
+//!
+//! Both functions are only `STABLE`, so Postgres calls them for every row and
+//! can't see their value when estimating selectivity. When the session's
+//! identity comes from a validated JWT, we replace the calls by constants at
+//! plan time. Plans which were folded like this are invalidated as soon as
+//! `pg_session_jwt.jwt` changes.
+
+use std::cell::Cell;
+
+use pgrx::{is_a, pg_sys, Internal};
+
+thread_local! {
+    static FOLDED_PLANS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Answer a `SupportRequestSimplify` with the constant built by `fold`, if any.
+pub fn simplify(
+    request: Internal,
+    fold: impl FnOnce(&pg_sys::FuncExpr) -> Option<*mut pg_sys::Const>,
+) -> Internal {
+    let request = request.unwrap().map_or(std::ptr::null_mut(), |datum| {
+        datum.cast_mut_ptr::<pg_sys::Node>()
+    });
+
+    let folded = unsafe {
+        if !is_a(request, pg_sys::NodeTag::T_SupportRequestSimplify) {
+            None
+        } else {
+            let request = request.cast::<pg_sys::SupportRequestSimplify>();
+            // without a planner, we might be building an expression which outlives
+            // the plan cache, like an index expression
+            if (*request).root.is_null() {
+                None
+            } else {
+                fold(&*(*request).fcall)
+            }
+        }
+    };
+
+    if folded.is_some() {
+        FOLDED_PLANS.set(true);
+    }
+
+    // support functions return a NULL pointer, rather than NULL, when they
+    // have nothing to offer
+    Internal::from(Some(pg_sys::Datum::from(
+        folded.map_or(std::ptr::null_mut(), |folded| folded.cast::<pg_sys::Node>()),
+    )))
+}
+
+/// Build a constant of the same type as the function call.
+pub fn make_const(
+    fcall: &pg_sys::FuncExpr,
+    value: Option<pg_sys::Datum>,
+) -> Option<*mut pg_sys::Const> {
+    unsafe {
+        Some(pg_sys::makeConst(
+            fcall.funcresulttype,
+            -1,
+            fcall.funccollid,
+            -1,
+            value.unwrap_or(pg_sys::Datum::from(0usize)),
+            value.is_none(),
+            false,
+        ))
+    }
+}
+
+/// Called whenever the JWT of the session changes.
+pub fn invalidate_folded_plans() {
+    if FOLDED_PLANS.replace(false) {
+        unsafe { pg_sys::ResetPlanCache() };
+    }
+}
@@ -0,0 +1,129 @@
+//! The JWT each backend is acting for, shared by all the backends.
+//!
+//! Like the statistics, this is only available when the extension is loaded
+//! via `shared_preload_libraries`.
+
+use std::cell::Cell;
+
+use pgrx::prelude::*;
+use pgrx::{pg_shmem_init, pg_sys, PGRXSharedMemory, PgLwLock};
+
+use crate::stats::{preloaded, require_preloaded, truncate};
+
+/// Maximum number of backends which are published at the same time.
+const MAX_SESSIONS: usize = 1024;
+
+static SESSIONS: PgLwLock<Sessions> = PgLwLock::new();
+
+thread_local! {
+    static EXIT_CALLBACK_REGISTERED: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Default)]
+struct Sessions(heapless::Vec<Session, MAX_SESSIONS>);
+
+unsafe impl PGRXSharedMemory for Sessions {}
+
+struct Session {
+    pid: i32,
+    /// The role of the backend, which decides who can see the session.
+    role: pg_sys::Oid,
+    sub: Option<heapless::String<128>>,
+    jti: i64,
+    exp: Option<i64>,
+    validated_at: f64,
+}
+
+/// The session of a backend, as published in shared memory.
+pub struct ActiveSession<'a> {
+    pub sub: Option<&'a str>,
+    pub jti: i64,
+    pub exp: Option<i64>,
+    pub validated_at: f64,
+}
+
+pub fn init() {
+    if preloaded() {
+        pg_shmem_init!(SESSIONS);
+    }
+}
+
+/// Publish the session of this backend, or remove it when `None`.
+pub fn publish(session: Option<ActiveSession>) {
+    if !preloaded() {
+        return;
+    }
+
+    let pid = unsafe { pg_sys::MyProcPid };
+    let role = unsafe { pg_sys::GetSessionUserId() };
+    let mut sessions = SESSIONS.exclusive();
+    let existing = sessions.0.iter().position(|session| session.pid == pid);
+    let Some(session) = session else {
+        if let Some(existing) = existing {
+            sessions.0.swap_remove(existing);
+        }
+        return;
+    };
+
+    let session = Session {
+        pid,
+        role,
+        sub: session.sub.map(truncate),
+        jti: session.jti,
+        exp: session.exp,
+        validated_at: session.validated_at,
+    };
+    match existing {
+        Some(existing) => sessions.0[existing] = session,
+        None => {
+            // once the array is full, new backends are not published anymore
+            if sessions.0.push(session).is_err() {
+                return;
+            }
+            drop(sessions);
+            if !EXIT_CALLBACK_REGISTERED.replace(true) {
+                unsafe { pg_sys::before_shmem_exit(Some(on_exit), pg_sys::Datum::from(0usize)) };
+            }
+        }
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn on_exit(_code: std::ffi::c_int, _arg: pg_sys::Datum) {
+    publish(None);
+}
+
+/// The published sessions, as `(pid, sub, jti, exp, validated_at)` rows.
+///
+/// Like `pg_stat_activity`, only the sessions of the roles the current user
+/// is a member of are visible, unless it has the privileges of
+/// `pg_read_all_stats`.
+pub fn rows() -> Vec<(i32, Option<String>, i64, Option<i64>, f64)> {
+    require_preloaded();
+
+    let user = unsafe { pg_sys::GetUserId() };
+    let read_all_stats = unsafe {
+        pg_sys::has_privs_of_role(
+            user,
+            pg_sys::get_role_oid(c"pg_read_all_stats".as_ptr(), false),
+        )
+    };
+
+    SESSIONS
+        .share()
+        .0
+        .iter()
+        .filter(|session| {
+            read_all_stats || unsafe { pg_sys::has_privs_of_role(user, session.role) }
+        })
+        .map(|session| {
+            (
+                session.pid,
+                session.sub.as_ref().map(|sub| sub.to_string()),
+                session.jti,
+                session.exp,
+                session.validated_at,
+            )
+        })
+        .collect()
+}
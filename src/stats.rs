@@ -0,0 +1,184 @@
+//! Validation statistics, shared by all the backends.
+//!
+//! The counters live in shared memory, so they are only collected when the
+//! extension is loaded via `shared_preload_libraries`. Cache hits and
+//! fallback reads happen on every access to the session, so each backend
+//! counts them locally and adds them to the shared counters at the end of the
+//! transaction.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use pgrx::prelude::*;
+use pgrx::{pg_shmem_init, pg_sys, PGRXSharedMemory, PgAtomic, PgLwLock};
+
+/// Maximum number of distinct failure reasons which are counted separately.
+const MAX_FAILURES: usize = 32;
+
+static PRELOADED: AtomicBool = AtomicBool::new(false);
+static VALIDATIONS: PgAtomic<AtomicU64> = PgAtomic::new();
+static CACHE_HITS: PgAtomic<AtomicU64> = PgAtomic::new();
+static FALLBACK_READS: PgAtomic<AtomicU64> = PgAtomic::new();
+static FAILURES: PgLwLock<Failures> = PgLwLock::new();
+
+thread_local! {
+    static PENDING_CACHE_HITS: Cell<u64> = const { Cell::new(0) };
+    static PENDING_FALLBACK_READS: Cell<u64> = const { Cell::new(0) };
+}
+
+#[derive(Default)]
+struct Failures(heapless::Vec<Failure, MAX_FAILURES>);
+
+unsafe impl PGRXSharedMemory for Failures {}
+
+struct Failure {
+    sqlstate: i32,
+    reason: heapless::String<128>,
+    count: u64,
+}
+
+pub fn init() {
+    if unsafe { !pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    pg_shmem_init!(VALIDATIONS);
+    pg_shmem_init!(CACHE_HITS);
+    pg_shmem_init!(FALLBACK_READS);
+    pg_shmem_init!(FAILURES);
+    PRELOADED.store(true, Ordering::Relaxed);
+}
+
+pub fn preloaded() -> bool {
+    PRELOADED.load(Ordering::Relaxed)
+}
+
+pub fn require_preloaded() {
+    if !preloaded() {
+        error_code!(
+            PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            "pg_session_jwt must be loaded via \"shared_preload_libraries\"",
+        );
+    }
+}
+
+/// A JWT was validated.
+pub fn validation() {
+    if preloaded() {
+        VALIDATIONS.get().fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The session was read from the cached JWT.
+pub fn cache_hit() {
+    if preloaded() {
+        PENDING_CACHE_HITS.set(PENDING_CACHE_HITS.get() + 1);
+    }
+}
+
+/// The claims were read from `request.jwt.claims`, because no JWK is defined.
+pub fn fallback_read() {
+    if preloaded() {
+        PENDING_FALLBACK_READS.set(PENDING_FALLBACK_READS.get() + 1);
+    }
+}
+
+/// Add the counts of this backend to the shared counters.
+pub fn flush() {
+    if !preloaded() {
+        return;
+    }
+    let cache_hits = PENDING_CACHE_HITS.take();
+    if cache_hits > 0 {
+        CACHE_HITS.get().fetch_add(cache_hits, Ordering::Relaxed);
+    }
+    let fallback_reads = PENDING_FALLBACK_READS.take();
+    if fallback_reads > 0 {
+        FALLBACK_READS
+            .get()
+            .fetch_add(fallback_reads, Ordering::Relaxed);
+    }
+}
+
+/// A JWT failed validation, with the packed SQLSTATE of the error.
+pub fn failure(sqlstate: i32, reason: &str) {
+    if !preloaded() {
+        return;
+    }
+    let reason = truncate(reason);
+    let mut failures = FAILURES.exclusive();
+    let existing = failures
+        .0
+        .iter_mut()
+        .find(|failure| failure.sqlstate == sqlstate && failure.reason == reason);
+    match existing {
+        Some(failure) => failure.count += 1,
+        None => {
+            // once the table is full, new reasons are not counted anymore
+            let _ = failures.0.push(Failure {
+                sqlstate,
+                reason,
+                count: 1,
+            });
+        }
+    }
+}
+
+/// Truncate a string to a fixed capacity, on a character boundary.
+pub fn truncate<const N: usize>(s: &str) -> heapless::String<N> {
+    let mut truncated = heapless::String::new();
+    for c in s.chars() {
+        if truncated.push(c).is_err() {
+            break;
+        }
+    }
+    truncated
+}
+
+/// The counters, as `(counter, sqlstate, reason, count)` rows.
+pub fn rows() -> Vec<(String, Option<String>, Option<String>, i64)> {
+    require_preloaded();
+    flush();
+
+    let counter = |name: &str, value: &AtomicU64| {
+        (
+            name.to_string(),
+            None,
+            None,
+            value.load(Ordering::Relaxed) as i64,
+        )
+    };
+    let mut rows = vec![
+        counter("validations", VALIDATIONS.get()),
+        counter("cache_hits", CACHE_HITS.get()),
+        counter("fallback_reads", FALLBACK_READS.get()),
+    ];
+    rows.extend(FAILURES.share().0.iter().map(|failure| {
+        (
+            "failures".to_string(),
+            Some(sqlstate(failure.sqlstate)),
+            Some(failure.reason.to_string()),
+            failure.count as i64,
+        )
+    }));
+    rows
+}
+
+/// Reset all the counters.
+pub fn reset() {
+    require_preloaded();
+    PENDING_CACHE_HITS.take();
+    PENDING_FALLBACK_READS.take();
+
+    VALIDATIONS.get().store(0, Ordering::Relaxed);
+    CACHE_HITS.get().store(0, Ordering::Relaxed);
+    FALLBACK_READS.get().store(0, Ordering::Relaxed);
+    FAILURES.exclusive().0.clear();
+}
+
+fn sqlstate(sqlerrcode: i32) -> String {
+    unsafe {
+        std::ffi::CStr::from_ptr(pg_sys::unpack_sql_state(sqlerrcode))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
@@ -0,0 +1,346 @@
+//! A background worker which fetches a JWK Set over HTTP(S), e.g. from an
+//! OIDC issuer, and publishes it in shared memory for all the backends, with
+//! the issuer and the signing algorithms of the OIDC discovery document.
+//!
+//! Like the statistics, this is only available when the extension is loaded
+//! via `shared_preload_libraries`. The worker only runs while a JWK Set is
+//! configured: it's registered at startup when one is, and otherwise started
+//! by the first session which needs the keys.
+
+use std::ffi::c_int;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use pgrx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, SignalWakeFlags};
+use pgrx::prelude::*;
+use pgrx::{pg_shmem_init, pg_sys, PGRXSharedMemory, PgAtomic, PgLwLock};
+use serde_json::{json, Value};
+
+use crate::gucs::{
+    NEON_AUTH_JWKS_ALLOW_HTTP, NEON_AUTH_JWKS_ALLOW_HTTP_RUNTIME_PARAM,
+    NEON_AUTH_JWKS_REFRESH_INTERVAL, NEON_AUTH_JWKS_URI, NEON_AUTH_OIDC_DISCOVERY,
+};
+use crate::stats::preloaded;
+
+/// Maximum size of the published JWK Set, once unsupported keys are removed.
+const MAX_JWKS_SIZE: usize = 16 * 1024;
+
+/// How long to wait before fetching the JWK Set again after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long to wait for the server to answer.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Incremented every time the published keys change.
+static GENERATION: PgAtomic<AtomicU64> = PgAtomic::new();
+static JWKS: PgLwLock<Jwks> = PgLwLock::new();
+/// The pid of the running worker, or 0.
+static WORKER_PID: PgAtomic<AtomicI32> = PgAtomic::new();
+/// Whether a session started a worker which isn't running yet.
+static WORKER_REQUESTED: PgAtomic<AtomicBool> = PgAtomic::new();
+
+#[derive(Default)]
+struct Jwks(heapless::Vec<u8, MAX_JWKS_SIZE>);
+
+unsafe impl PGRXSharedMemory for Jwks {}
+
+pub fn init() {
+    if !preloaded() {
+        return;
+    }
+    pg_shmem_init!(GENERATION);
+    pg_shmem_init!(JWKS);
+    pg_shmem_init!(WORKER_PID);
+    pg_shmem_init!(WORKER_REQUESTED);
+
+    if enabled() {
+        worker().load();
+    }
+}
+
+fn worker() -> BackgroundWorkerBuilder {
+    BackgroundWorkerBuilder::new("pg_session_jwt JWKS refresher")
+        .set_function("jwks_refresher_main")
+        .set_library("pg_session_jwt")
+        .enable_shmem_access(None)
+        .set_restart_time(Some(RETRY_INTERVAL))
+}
+
+/// Start the worker when a JWK Set is configured, but no worker runs, e.g.
+/// because it was only configured after the server started.
+pub fn ensure_started() {
+    if !preloaded() || !enabled() || WORKER_PID.get().load(Ordering::Acquire) != 0 {
+        return;
+    }
+    let requested = WORKER_REQUESTED.get();
+    if requested
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+    if worker().load_dynamic().is_err() {
+        requested.store(false, Ordering::Release);
+        warning!("could not start the JWKS refresher, max_worker_processes may be too low");
+    }
+}
+
+/// The URL of the JWK Set, or of an OIDC discovery document.
+pub fn jwks_uri() -> Option<String> {
+    NEON_AUTH_JWKS_URI
+        .get()
+        .map(|uri| uri.to_string_lossy().into_owned())
+        .filter(|uri| !uri.is_empty())
+}
+
+/// The URL of the OIDC discovery document, or its file relative to the data
+/// directory.
+pub fn oidc_discovery() -> Option<String> {
+    NEON_AUTH_OIDC_DISCOVERY
+        .get()
+        .map(|location| location.to_string_lossy().into_owned())
+        .filter(|location| !location.is_empty())
+}
+
+/// Whether the worker fetches keys.
+pub fn enabled() -> bool {
+    jwks_uri().is_some() || oidc_discovery().is_some()
+}
+
+/// The generation of the published keys, which changes with them.
+pub fn generation() -> u64 {
+    if !preloaded() {
+        return 0;
+    }
+    GENERATION.get().load(Ordering::Acquire)
+}
+
+/// The published JWK Set, which only has supported keys, or nothing. Its
+/// `oidc` member has the `issuer` and `id_token_signing_alg_values_supported`
+/// of the OIDC discovery document.
+pub fn jwks() -> Vec<u8> {
+    if !preloaded() {
+        return vec![];
+    }
+    JWKS.share().0.to_vec()
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn jwks_refresher_main(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+
+    // the worker registered at startup and one started by a session may both
+    // be starting
+    let pid = unsafe { pg_sys::MyProcPid };
+    if WORKER_PID
+        .get()
+        .compare_exchange(0, pid, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+    unsafe { pg_sys::before_shmem_exit(Some(on_exit), pg_sys::Datum::from(0usize)) };
+    WORKER_REQUESTED.get().store(false, Ordering::Release);
+
+    let mut next_refresh = Instant::now();
+    loop {
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext::PGC_SIGHUP) };
+            // the URL may have changed
+            next_refresh = Instant::now();
+        }
+        // exiting cleanly unregisters the worker, until a session starts it again
+        if !enabled() {
+            publish(&[]);
+            break;
+        }
+        if next_refresh <= Instant::now() {
+            next_refresh = Instant::now() + refresh();
+        }
+
+        let timeout = next_refresh.saturating_duration_since(Instant::now());
+        if !BackgroundWorker::wait_latch(Some(timeout)) {
+            break;
+        }
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn on_exit(_code: c_int, _arg: pg_sys::Datum) {
+    WORKER_PID.get().store(0, Ordering::Release);
+}
+
+/// Fetch and publish the JWK Set, and return when to fetch it again.
+///
+/// When it can't be fetched, the previous keys are kept.
+fn refresh() -> Duration {
+    let interval = Duration::from_secs(NEON_AUTH_JWKS_REFRESH_INTERVAL.get() as u64);
+    match fetch() {
+        Ok((jwks, max_age)) => {
+            publish(jwks.as_bytes());
+            // the server may ask for the keys to be fetched sooner
+            max_age.map_or(interval, |max_age| {
+                max_age.clamp(Duration::from_secs(1), interval)
+            })
+        }
+        Err(e) => {
+            warning!("could not refresh the JWK Set, keeping the previous keys: {e}");
+            RETRY_INTERVAL.min(interval)
+        }
+    }
+}
+
+fn publish(jwks: &[u8]) {
+    let mut published = JWKS.exclusive();
+    if published.0.as_slice() == jwks {
+        return;
+    }
+    published.0.clear();
+    // fetch() makes sure it fits
+    let _ = published.0.extend_from_slice(jwks);
+    GENERATION.get().fetch_add(1, Ordering::Release);
+}
+
+/// Fetch the JWK Set, with only the supported keys, and how long it can be
+/// cached.
+fn fetch() -> Result<(String, Option<Duration>), String> {
+    let mut published = serde_json::Map::new();
+    let mut max_age = None;
+
+    let mut jwks_uri = jwks_uri();
+    if let Some(location) = oidc_discovery() {
+        let (discovery, discovery_max_age) =
+            load(&location).map_err(|e| format!("\"{location}\": {e}"))?;
+        let (oidc, discovered_jwks_uri) = provider(&discovery)
+            .map_err(|e| format!("invalid OIDC discovery document \"{location}\": {e}"))?;
+        published.insert("oidc".into(), oidc);
+        // pg_session_jwt.jwks_uri takes precedence
+        jwks_uri.get_or_insert(discovered_jwks_uri);
+        max_age = discovery_max_age;
+    }
+    let Some(jwks_uri) = jwks_uri else {
+        return Err("no JWK Set is configured".into());
+    };
+
+    let (keys, jwks_max_age) = fetch_keys(&jwks_uri).map_err(|e| format!("\"{jwks_uri}\": {e}"))?;
+    published.insert("keys".into(), keys.into());
+    let max_age = shortest(max_age, jwks_max_age);
+
+    let jwks = Value::Object(published).to_string();
+    if jwks.len() > MAX_JWKS_SIZE {
+        return Err(format!("the JWK Set is larger than {MAX_JWKS_SIZE} bytes"));
+    }
+    Ok((jwks, max_age))
+}
+
+/// The issuer and the signing algorithms of an OIDC discovery document, and
+/// the URL of its JWK Set.
+fn provider(discovery: &Value) -> Result<(Value, String), String> {
+    let member = |name: &str| {
+        discovery
+            .get(name)
+            .ok_or_else(|| format!("\"{name}\" is missing"))
+    };
+    let issuer = member("issuer")?
+        .as_str()
+        .ok_or("\"issuer\" must be a string")?;
+    let jwks_uri = member("jwks_uri")?
+        .as_str()
+        .ok_or("\"jwks_uri\" must be a string")?;
+    let algs = member("id_token_signing_alg_values_supported")?
+        .as_array()
+        .filter(|algs| algs.iter().all(Value::is_string))
+        .ok_or("\"id_token_signing_alg_values_supported\" must be an array of strings")?;
+    if !algs.iter().any(|alg| alg == "EdDSA") {
+        warning!(
+            "the OIDC issuer \"{issuer}\" doesn't sign with EdDSA, the only supported algorithm"
+        );
+    }
+
+    let oidc = json!({
+        "issuer": issuer,
+        "id_token_signing_alg_values_supported": algs,
+    });
+    Ok((oidc, jwks_uri.to_string()))
+}
+
+/// The supported keys of a JWK Set, and how long they can be cached.
+fn fetch_keys(uri: &str) -> Result<(Vec<Value>, Option<Duration>), String> {
+    let (document, max_age) = get(uri)?;
+
+    // an OIDC discovery document points to the JWK Set
+    let (document, max_age) = match document.get("jwks_uri").and_then(Value::as_str) {
+        Some(jwks_uri) if document.get("keys").is_none() => {
+            let (jwks, jwks_max_age) = get(jwks_uri)?;
+            (jwks, shortest(max_age, jwks_max_age))
+        }
+        _ => (document, max_age),
+    };
+
+    let keys = document
+        .get("keys")
+        .and_then(Value::as_array)
+        .ok_or("the document is not a JWK Set")?;
+    let keys: Vec<Value> = keys
+        .iter()
+        .filter(|jwk| match crate::auth::check_jwk(jwk) {
+            Ok(()) => true,
+            Err(e) => {
+                debug1!("skipping an unsupported key of \"{uri}\": {e}");
+                false
+            }
+        })
+        .cloned()
+        .collect();
+    if keys.is_empty() {
+        return Err("the JWK Set has no supported key".into());
+    }
+    Ok((keys, max_age))
+}
+
+/// Read a JSON document from a URL, or from a file of the data directory.
+fn load(location: &str) -> Result<(Value, Option<Duration>), String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return get(location);
+    }
+    let document = crate::auth::read_data_file(location)?;
+    let document = serde_json::from_slice(&document).map_err(|e| e.to_string())?;
+    Ok((document, None))
+}
+
+/// GET a JSON document, and the `max-age` of its `Cache-Control` header.
+///
+/// Only over HTTPS, including redirects, unless plain HTTP is allowed.
+fn get(uri: &str) -> Result<(Value, Option<Duration>), String> {
+    let allow_http = NEON_AUTH_JWKS_ALLOW_HTTP.get();
+    if !allow_http && !uri.starts_with("https://") {
+        return Err(format!(
+            "only https:// URLs are allowed, unless {NEON_AUTH_JWKS_ALLOW_HTTP_RUNTIME_PARAM} is on"
+        ));
+    }
+    let agent = ureq::AgentBuilder::new()
+        .timeout(HTTP_TIMEOUT)
+        .https_only(!allow_http)
+        .build();
+    let response = agent.get(uri).call().map_err(|e| e.to_string())?;
+    let max_age = response.header("Cache-Control").and_then(max_age);
+    let document = response.into_string().map_err(|e| e.to_string())?;
+    let document = serde_json::from_str(&document).map_err(|e| e.to_string())?;
+    Ok((document, max_age))
+}
+
+fn shortest(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn max_age(cache_control: &str) -> Option<Duration> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .find_map(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs)
+}
@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::ffi::c_void;
+
+use pgrx::{pg_guard, pg_sys};
+
+use crate::auth::{self, SessionState};
+use crate::stats;
+
+thread_local! {
+    /// Session state as it was before its first change within a
+    /// (sub)transaction, tagged with the nesting level of that (sub)transaction.
+    static SAVED_STATES: RefCell<Vec<(i32, SessionState)>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn init() {
+    unsafe {
+        pg_sys::RegisterXactCallback(Some(xact_callback), std::ptr::null_mut());
+        pg_sys::RegisterSubXactCallback(Some(subxact_callback), std::ptr::null_mut());
+    }
+}
+
+/// Remember the session state before it gets modified, the same way Postgres
+/// saves the value of a GUC on its first change within a (sub)transaction.
+pub fn save_session_state() {
+    let nest_level = unsafe { pg_sys::GetCurrentTransactionNestLevel() };
+
+    SAVED_STATES.with_borrow_mut(|saved| {
+        if !matches!(saved.last(), Some((level, _)) if *level >= nest_level) {
+            saved.push((nest_level, auth::session_state()));
+        }
+    });
+}
+
+#[pg_guard]
+unsafe extern "C" fn xact_callback(event: pg_sys::XactEvent::Type, _arg: *mut c_void) {
+    match event {
+        pg_sys::XactEvent::XACT_EVENT_COMMIT | pg_sys::XactEvent::XACT_EVENT_PREPARE => {
+            SAVED_STATES.take();
+            auth::end_transaction_scope();
+            stats::flush();
+        }
+        pg_sys::XactEvent::XACT_EVENT_ABORT => {
+            stats::flush();
+            // the oldest saved state is the one from before the transaction
+            if let Some((_, state)) = SAVED_STATES.take().into_iter().next() {
+                auth::restore_session_state(state);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn subxact_callback(
+    event: pg_sys::SubXactEvent::Type,
+    _my_subid: pg_sys::SubTransactionId,
+    _parent_subid: pg_sys::SubTransactionId,
+    _arg: *mut c_void,
+) {
+    let nest_level = pg_sys::GetCurrentTransactionNestLevel();
+
+    match event {
+        pg_sys::SubXactEvent::SUBXACT_EVENT_COMMIT_SUB => SAVED_STATES.with_borrow_mut(|saved| {
+            if matches!(saved.last(), Some((level, _)) if *level == nest_level) {
+                let (_, state) = saved.pop().unwrap();
+                // the parent keeps its own saved state, which is older
+                if !matches!(saved.last(), Some((level, _)) if *level == nest_level - 1) {
+                    saved.push((nest_level - 1, state));
+                }
+            }
+        }),
+        pg_sys::SubXactEvent::SUBXACT_EVENT_ABORT_SUB => {
+            let state = SAVED_STATES.with_borrow_mut(|saved| {
+                let mut state = None;
+                while matches!(saved.last(), Some((level, _)) if *level >= nest_level) {
+                    state = saved.pop().map(|(_, state)| state);
+                }
+                state
+            });
+            if let Some(state) = state {
+                auth::restore_session_state(state);
+            }
+        }
+        _ => {}
+    }
+}
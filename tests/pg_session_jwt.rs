@@ -1,5 +1,9 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
 use std::process::ExitCode;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use base64ct::{Base64UrlUnpadded, Encoding};
 use ed25519_dalek::{Signature, Signer, SigningKey};
@@ -40,6 +44,10 @@ fn main() -> ExitCode {
         "test_jwt_claim_sub_when_claims_not_set",
         test_jwt_claim_sub_when_claims_not_set,
     ));
+    tests.push(test_without_jwk(
+        "test_jwt_claim_sub_when_claims_change",
+        test_jwt_claim_sub_when_claims_change,
+    ));
     tests.push(test_fn(
         "test_session_with_jwk",
         None,
@@ -53,6 +61,136 @@ fn main() -> ExitCode {
         "test_session_fallback_when_not_set",
         test_session_fallback_when_not_set,
     ));
+    tests.push(test_fn(
+        "test_transaction_init",
+        None,
+        test_transaction_init,
+    ));
+    tests.push(test_fn(
+        "test_transaction_init_after_session_init",
+        None,
+        test_transaction_init_after_session_init,
+    ));
+    tests.push(test_fn("test_session_reset", None, test_session_reset));
+    tests.push(test_fn("test_discard_all", None, test_discard_all));
+    tests.push(test_fn(
+        "test_session_init_rollback",
+        None,
+        test_session_init_rollback,
+    ));
+    tests.push(test_fn(
+        "test_session_init_rollback_to_savepoint",
+        None,
+        test_session_init_rollback_to_savepoint,
+    ));
+    tests.push(test_fn(
+        "test_session_across_transactions",
+        None,
+        test_session_across_transactions,
+    ));
+    tests.push(test_fn(
+        "test_user_id_folded_into_index_scan",
+        None,
+        test_user_id_folded_into_index_scan,
+    ));
+    tests.push(test_fn(
+        "test_folded_plans_invalidated",
+        None,
+        test_folded_plans_invalidated,
+    ));
+    tests.push(test_fn(
+        "test_planning_does_not_validate",
+        None,
+        test_planning_does_not_validate,
+    ));
+    tests.push(test_fn(
+        "test_audit_log_settings",
+        None,
+        test_audit_log_settings,
+    ));
+    tests.push(test_fn("test_audit_log_json", None, test_audit_log_json));
+    tests.push(test_fn(
+        "test_audit_log_failures",
+        None,
+        test_audit_log_failures,
+    ));
+    tests.push(test_fn("test_session_info", None, test_session_info));
+    tests.push(test_fn(
+        "test_session_info_before_init",
+        None,
+        test_session_info_before_init,
+    ));
+    tests.push(test_without_jwk(
+        "test_session_info_fallback",
+        test_session_info_fallback,
+    ));
+    tests.push(test_fn("test_sqlstates", None, test_sqlstates));
+    tests.push(test_fn("test_validate_jwt", None, test_validate_jwt));
+    tests.push(test_without_jwk("test_jwt_decode", test_jwt_decode));
+    tests.push(test_fn(
+        "test_jwt_sign_restricted",
+        None,
+        test_jwt_sign_restricted,
+    ));
+    tests.push(test_fn("test_jwt_sign", None, test_jwt_sign));
+    tests.push(test_fn("test_issue_token", None, test_issue_token));
+    tests.push(test_fn("test_jwks", None, test_jwks));
+    tests.push(test_without_jwk(
+        "test_jwks_without_jwk",
+        test_jwks_without_jwk,
+    ));
+    tests.push(test_without_jwk("test_jwk_thumbprint", test_jwk_thumbprint));
+    tests.push(test_fn("test_thumbprint_kid", None, test_thumbprint_kid));
+    tests.push(test_fn_with_jwk(
+        "test_jwk_metadata",
+        None,
+        create_bound_jwk,
+        test_jwk_metadata,
+    ));
+    tests.push(test_fn_with_jwk(
+        "test_jwk_other_members",
+        None,
+        create_jwk_with_x5t,
+        test_jwk_other_members,
+    ));
+    let err = "pg_session_jwt.jwk is not a signature verification key";
+    tests.push(test_fn_with_jwk(
+        "test_jwk_encryption_key",
+        Some(err),
+        create_encryption_jwk,
+        test_jwk_encryption_key,
+    ));
+    let err = "JWK used after it has expired";
+    tests.push(test_fn_with_jwk(
+        "test_jwk_expired",
+        Some(err),
+        create_expired_jwk,
+        test_jwk_expired,
+    ));
+    tests.push(test_without_jwk(
+        "test_jwks_file_setting",
+        test_jwks_file_setting,
+    ));
+    tests.push(test_without_jwk(
+        "test_jwks_file_reload",
+        test_jwks_file_reload,
+    ));
+    tests.push(test_without_jwk(
+        "test_verification_keys_table",
+        test_verification_keys_table,
+    ));
+    if preloaded() {
+        tests.push(test_fn("test_stats", None, test_stats));
+        tests.push(test_fn("test_active_sessions", None, test_active_sessions));
+        tests.push(test_without_jwk("test_jwks_uri", test_jwks_uri));
+        tests.push(test_without_jwk("test_oidc_discovery", test_oidc_discovery));
+        tests.push(test_without_jwk(
+            "test_jwks_uri_requires_https",
+            test_jwks_uri_requires_https,
+        ));
+    } else {
+        tests.push(test_without_jwk("test_not_preloaded", test_not_preloaded));
+    }
 
     run(&args, tests).exit_code()
 }
@@ -60,18 +198,35 @@ fn main() -> ExitCode {
 // bgworker process exits after execution, because of that we don't need to test case for more
 // than one JWT
 fn test_fn<F>(name: &str, error: Option<&'static str>, f: F) -> Trial
+where
+    F: for<'a, 'b> FnOnce(&'a SigningKey, &'b mut postgres::Client) -> Result<(), postgres::Error>
+        + Send
+        + 'static,
+{
+    test_fn_with_jwk(name, error, create_jwk, f)
+}
+
+// Helper function for tests that need a JWK with more members
+fn test_fn_with_jwk<F>(
+    name: &str,
+    error: Option<&'static str>,
+    jwk: fn(&SigningKey) -> String,
+    f: F,
+) -> Trial
 where
     F: for<'a, 'b> FnOnce(&'a SigningKey, &'b mut postgres::Client) -> Result<(), postgres::Error>
         + Send
         + 'static,
 {
     let sk = SigningKey::generate(&mut OsRng);
-    let jwk = create_jwk(&sk);
+    let jwk = jwk(&sk);
     let options = format!("-c {NEON_AUTH_JWK_RUNTIME_PARAM}={jwk}");
 
     Trial::test(name, move || {
-        pgrx_tests::run_test(Some(&options), error, vec![], move |tx| f(&sk, tx))
-            .map_err(libtest_mimic::Failed::from)
+        pgrx_tests::run_test(Some(&options), error, postgresql_conf(), move |tx| {
+            f(&sk, tx)
+        })
+        .map_err(libtest_mimic::Failed::from)
     })
 }
 
@@ -81,10 +236,25 @@ where
     F: FnOnce(&mut postgres::Client) -> Result<(), postgres::Error> + Send + 'static,
 {
     Trial::test(name, move || {
-        pgrx_tests::run_test(None, None, vec![], f).map_err(libtest_mimic::Failed::from)
+        pgrx_tests::run_test(None, None, postgresql_conf(), f).map_err(libtest_mimic::Failed::from)
     })
 }
 
+// The tests run twice: as the `tests` target without preloading the extension, and as the
+// `preloaded` target, which includes this file, with the extension in `shared_preload_libraries`
+// for the features backed by shared memory.
+fn preloaded() -> bool {
+    env!("CARGO_CRATE_NAME") == "preloaded"
+}
+
+fn postgresql_conf() -> Vec<&'static str> {
+    if preloaded() {
+        vec!["shared_preload_libraries = 'pg_session_jwt'"]
+    } else {
+        vec![]
+    }
+}
+
 fn wrong_txid(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
     let jwt1 = sign_jwt(sk, r#"{"kid":1}"#, r#"{"jti":1}"#);
     let jwt2 = sign_jwt(sk, r#"{"kid":1}"#, r#"{"jti":2}"#);
@@ -237,6 +407,23 @@ fn test_jwt_claim_sub_when_claims_not_set(
     Ok(())
 }
 
+fn test_jwt_claim_sub_when_claims_change(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    tx.execute("SET request.jwt.claims = '{\"sub\":\"foo\"}'", &[])?;
+    let user_id: Option<String> = tx.query_one("SELECT auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, Some("foo".to_string()));
+
+    // cached claims must not outlive the value they were parsed from
+    tx.execute("SET request.jwt.claims = '{\"sub\":\"bar\"}'", &[])?;
+    let user_id: Option<String> = tx.query_one("SELECT auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, Some("bar".to_string()));
+
+    tx.execute("SET request.jwt.claims = ''", &[])?;
+    let user_id: Option<String> = tx.query_one("SELECT auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, None);
+
+    Ok(())
+}
+
 fn test_session_with_jwk(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
     let header = r#"{"kid":1}"#;
     let jwt = sign_jwt(sk, header, r#"{"sub":"jwt-user","jti":1,"role":"admin"}"#);
@@ -291,16 +478,1344 @@ fn test_session_fallback_when_not_set(tx: &mut postgres::Client) -> Result<(), p
     Ok(())
 }
 
-static NEON_AUTH_JWK_RUNTIME_PARAM: &str = "pg_session_jwt.jwk";
+fn test_transaction_init(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let jwt = sign_jwt(sk, header, r#"{"sub":"foo","jti":1}"#);
 
-fn sign_jwt(sk: &SigningKey, header: &str, payload: impl ToString) -> String {
-    let header = Base64UrlUnpadded::encode_string(header.as_bytes());
-    let payload = Base64UrlUnpadded::encode_string(payload.to_string().as_bytes());
+    tx.execute("select auth.init()", &[])?;
 
-    let message = format!("{header}.{payload}");
-    let sig: Signature = sk.sign(message.as_bytes());
-    let base64_sig = Base64UrlUnpadded::encode_string(&sig.to_bytes());
-    format!("{message}.{base64_sig}")
+    let mut txn = tx.transaction()?;
+    txn.execute("select auth.jwt_transaction_init($1)", &[&jwt])?;
+    let user_id: Option<String> = txn.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, Some("foo".to_string()));
+    txn.commit()?;
+
+    // identity must not leak past the end of the transaction
+    let user_id: Option<String> = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, None, "Should return NULL after the transaction ended");
+
+    let jwt: Option<String> = tx
+        .query_one("select current_setting('pg_session_jwt.jwt', true)", &[])?
+        .get(0);
+    assert!(jwt.unwrap_or_default().is_empty());
+
+    Ok(())
+}
+
+fn test_transaction_init_after_session_init(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let jwt1 = sign_jwt(sk, header, r#"{"sub":"foo","jti":1}"#);
+    let jwt2 = sign_jwt(sk, header, r#"{"sub":"bar","jti":2}"#);
+
+    tx.execute("select auth.init()", &[])?;
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt1])?;
+
+    let mut txn = tx.transaction()?;
+    txn.execute("select auth.jwt_transaction_init($1)", &[&jwt2])?;
+    let user_id: String = txn.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, "bar");
+    txn.commit()?;
+
+    // we are back to the session-level JWT
+    let user_id: String = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, "foo");
+
+    Ok(())
+}
+
+fn test_session_reset(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let jwt1 = sign_jwt(sk, header, r#"{"sub":"foo","jti":2}"#);
+    let jwt2 = sign_jwt(sk, header, r#"{"sub":"bar","jti":1}"#);
+
+    tx.execute("select auth.init()", &[])?;
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt1])?;
+    tx.execute("select auth.jwt_session_reset()", &[])?;
+
+    let user_id: Option<String> = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(
+        user_id, None,
+        "Should return NULL after the session was reset"
+    );
+
+    // the token ID counter starts over for the next user
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt2])?;
+    let user_id: String = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, "bar");
+
+    Ok(())
+}
+
+fn test_discard_all(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let jwt1 = sign_jwt(sk, header, r#"{"sub":"foo","jti":2}"#);
+    let jwt2 = sign_jwt(sk, header, r#"{"sub":"bar","jti":1}"#);
+
+    tx.execute("select auth.init()", &[])?;
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt1])?;
+    tx.batch_execute("DISCARD ALL")?;
+
+    let user_id: Option<String> = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, None, "Should return NULL after DISCARD ALL");
+
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt2])?;
+    tx.batch_execute("RESET ALL")?;
+
+    let user_id: Option<String> = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, None, "Should return NULL after RESET ALL");
+
+    Ok(())
+}
+
+fn test_session_init_rollback(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let jwt = sign_jwt(sk, header, r#"{"sub":"foo","jti":1}"#);
+
+    tx.execute("select auth.init()", &[])?;
+
+    let mut txn = tx.transaction()?;
+    txn.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+    txn.rollback()?;
+
+    let user_id: Option<String> = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, None, "Should return NULL after ROLLBACK");
+
+    // the token ID was rolled back as well
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+    let user_id: String = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, "foo");
+
+    Ok(())
+}
+
+fn test_session_init_rollback_to_savepoint(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let jwt1 = sign_jwt(sk, header, r#"{"sub":"foo","jti":1}"#);
+    let jwt2 = sign_jwt(sk, header, r#"{"sub":"bar","jti":2}"#);
+
+    tx.execute("select auth.init()", &[])?;
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt1])?;
+
+    let mut txn = tx.transaction()?;
+    let mut savepoint = txn.savepoint("before_bar")?;
+    savepoint.execute("select auth.jwt_session_init($1)", &[&jwt2])?;
+    savepoint.rollback()?;
+
+    let user_id: String = txn.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(
+        user_id, "foo",
+        "Should return sub from before the savepoint"
+    );
+
+    // the token ID was rolled back as well
+    txn.execute("select auth.jwt_session_init($1)", &[&jwt2])?;
+    txn.commit()?;
+
+    let user_id: String = tx.query_one("select auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, "bar");
+
+    Ok(())
+}
+
+fn test_session_across_transactions(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let foo = json!({"sub": "foo", "jti": 1, "roles": ["admin", "user"]});
+    let jwt1 = sign_jwt(sk, header, &foo);
+
+    tx.execute("select auth.init()", &[])?;
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt1])?;
+
+    // the cached jsonb is copied out on every call, and must survive the end
+    // of the transactions it was returned in
+    for jti in 2..5 {
+        let mut txn = tx.transaction()?;
+        for _ in 0..3 {
+            let distinct: i64 = txn
+                .query_one(
+                    "select count(distinct auth.session()::text) from generate_series(1, 100)",
+                    &[],
+                )?
+                .get(0);
+            assert_eq!(distinct, 1);
+        }
+        txn.commit()?;
+        assert_eq!(session(tx)?, foo);
+
+        let bar = json!({"sub": "bar", "jti": jti});
+        let mut txn = tx.transaction()?;
+        let jwt2 = sign_jwt(sk, header, &bar);
+        txn.execute("select auth.jwt_session_init($1)", &[&jwt2])?;
+        assert_eq!(session(&mut txn)?, bar);
+        txn.rollback()?;
+
+        assert_eq!(session(tx)?, foo);
+    }
+
+    Ok(())
+}
+
+fn session(
+    client: &mut impl postgres::GenericClient,
+) -> Result<serde_json::Value, postgres::Error> {
+    let session: String = client.query_one("select auth.session()::text", &[])?.get(0);
+    Ok(serde_json::from_str(&session).unwrap())
+}
+
+fn explain(tx: &mut postgres::Client, query: &str) -> Result<String, postgres::Error> {
+    let plan = tx
+        .query(&format!("EXPLAIN (COSTS OFF) {query}"), &[])?
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    Ok(plan.join("\n"))
+}
+
+fn test_user_id_folded_into_index_scan(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let jwt = sign_jwt(sk, header, r#"{"sub":"user-42","jti":1}"#);
+
+    tx.batch_execute(
+        "CREATE TEMP TABLE documents (id int, owner text);
+        INSERT INTO documents SELECT i, 'user-' || (i % 1000) FROM generate_series(1, 100000) i;
+        CREATE INDEX ON documents (owner);
+        ANALYZE documents;",
+    )?;
+
+    tx.execute("select auth.init()", &[])?;
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+
+    let plan = explain(tx, "SELECT * FROM documents WHERE owner = auth.user_id()")?;
+    assert!(plan.contains("Index"), "Should use the index:\n{plan}");
+    assert!(
+        plan.contains("(owner = 'user-42'::text)"),
+        "Should fold auth.user_id() into a constant:\n{plan}"
+    );
+
+    let plan = explain(
+        tx,
+        "SELECT * FROM documents WHERE owner = auth.session()->>'sub'",
+    )?;
+    assert!(plan.contains("Index"), "Should use the index:\n{plan}");
+    assert!(
+        !plan.contains("auth.session()"),
+        "Should fold auth.session() into a constant:\n{plan}"
+    );
+
+    Ok(())
+}
+
+fn test_folded_plans_invalidated(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    let header = r#"{"kid":1}"#;
+    let jwt1 = sign_jwt(sk, header, r#"{"sub":"foo","jti":1}"#);
+    let jwt2 = sign_jwt(sk, header, r#"{"sub":"bar","jti":2}"#);
+
+    tx.execute("select auth.init()", &[])?;
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt1])?;
+    tx.batch_execute("PREPARE current_user_id AS SELECT auth.user_id()")?;
+
+    let user_id: String = tx.query_one("EXECUTE current_user_id", &[])?.get(0);
+    assert_eq!(user_id, "foo");
+
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt2])?;
+    let user_id: String = tx.query_one("EXECUTE current_user_id", &[])?.get(0);
+    assert_eq!(
+        user_id, "bar",
+        "Should not reuse a plan folded for another JWT"
+    );
+
+    let mut txn = tx.transaction()?;
+    txn.execute("select auth.jwt_session_reset()", &[])?;
+    let user_id: Option<String> = txn.query_one("EXECUTE current_user_id", &[])?.get(0);
+    assert_eq!(user_id, None);
+    txn.rollback()?;
+
+    let user_id: String = tx.query_one("EXECUTE current_user_id", &[])?.get(0);
+    assert_eq!(
+        user_id, "bar",
+        "Should not reuse a plan folded before ROLLBACK"
+    );
+
+    Ok(())
+}
+
+fn test_planning_does_not_validate(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    tx.execute("select auth.init()", &[])?;
+
+    let query = "SELECT 1 WHERE auth.user_id() = 'user1'";
+    let jwt = sign_jwt(sk, r#"{"kid":1}"#, r#"{"sub":"user1","jti":1}"#);
+    tx.execute(&format!("SET pg_session_jwt.jwt = '{jwt}'"), &[])?;
+
+    let plan = explain(tx, query)?;
+    assert!(
+        plan.contains("auth.user_id()"),
+        "Should not fold a JWT which isn't validated yet:\n{plan}"
+    );
+    let from_cache: bool = tx
+        .query_one("SELECT from_cache FROM auth.session_info()", &[])?
+        .get(0);
+    assert!(!from_cache, "Planning should not validate the JWT");
+
+    // the JWT is validated when the query runs
+    assert_eq!(tx.query(query, &[])?.len(), 1);
+    let plan = explain(tx, query)?;
+    assert!(
+        !plan.contains("auth.user_id()"),
+        "Should fold the validated JWT:\n{plan}"
+    );
+
+    Ok(())
+}
+
+fn test_audit_log_settings(
+    _sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    tx.execute("select auth.init()", &[])?;
+
+    let level: String = tx
+        .query_one("SHOW pg_session_jwt.audit_log_level", &[])?
+        .get(0);
+    assert_eq!(level, "log");
+    let on: String = tx
+        .query_one("SHOW pg_session_jwt.audit_log_on", &[])?
+        .get(0);
+    assert_eq!(on, "validate", "Should only audit validations by default");
+    let failures: String = tx
+        .query_one("SHOW pg_session_jwt.audit_log_failures_per_second", &[])?
+        .get(0);
+    assert_eq!(failures, "10");
+
+    // ordinary users must not be able to silence the audit log
+    let err = tx
+        .execute("SET pg_session_jwt.audit_log_level = 'off'", &[])
+        .unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{err}");
+
+    Ok(())
+}
+
+fn test_audit_log_json(sk: &SigningKey, _tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let (mut client, notices) = audited_client(sk)?;
+    client.batch_execute(
+        "SET pg_session_jwt.audit_log_claims = 'role, email';
+         SET pg_session_jwt.audit_log_redact = 'sub, email'",
+    )?;
+
+    let payload = json!({"sub": "user1", "jti": 1, "role": "admin", "email": "user1@example.com"});
+    let jwt = sign_jwt(sk, r#"{"kid":1}"#, payload);
+    client.execute("select auth.init()", &[])?;
+    client.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+
+    // every event is a single line of JSON
+    let multiline = notices
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|notice| notice.contains('\n'));
+    assert!(!multiline);
+    let events = audit_events(&notices);
+    let [event] = events.as_slice() else {
+        panic!("expected a single audit event: {events:?}");
+    };
+    assert_eq!(event["event"], "jwt_validated");
+    assert_eq!(event["outcome"], "success");
+    assert_eq!(event["jti"], 1);
+    assert_eq!(event["kid"], 1);
+    assert_eq!(event["sub"], "[REDACTED]");
+    assert_eq!(
+        event["claims"],
+        json!({"role": "admin", "email": "[REDACTED]"})
+    );
+
+    Ok(())
+}
+
+fn test_audit_log_failures(
+    sk: &SigningKey,
+    _tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    let (mut client, notices) = audited_client(sk)?;
+    client.batch_execute("SET pg_session_jwt.audit_log_failures_per_second = 2")?;
+    client.execute("select auth.init()", &[])?;
+    let client_addr: String = client
+        .query_one("SELECT coalesce(host(inet_client_addr()), '[local]')", &[])?
+        .get(0);
+
+    // signed by another key
+    let other = SigningKey::generate(&mut OsRng);
+    let payload = json!({"sub": "mallory", "jti": 1});
+    let forged = sign_jwt(&other, r#"{"kid":"key-9"}"#, payload);
+    for _ in 0..5 {
+        client
+            .execute("select auth.jwt_session_init($1)", &[&forged])
+            .unwrap_err();
+    }
+
+    // the claims are logged as found in the token, and the rest is suppressed
+    let events = audit_events(&notices);
+    assert_eq!(events.len(), 2, "{events:?}");
+    for event in &events {
+        assert_eq!(event["event"], "jwt_validated");
+        assert_eq!(event["outcome"], "failure");
+        assert_eq!(event["reason"], "invalid JWT signature");
+        assert_eq!(event["sub"], "mallory");
+        assert_eq!(event["kid"], "key-9");
+        assert_eq!(event["client_addr"], client_addr.as_str(), "{event}");
+        assert!(event.get("suppressed").is_none());
+    }
+
+    // the next event, in another second, reports how many were suppressed
+    std::thread::sleep(Duration::from_millis(1100));
+    client
+        .execute("select auth.jwt_session_init($1)", &[&forged])
+        .unwrap_err();
+    let events = audit_events(&notices);
+    assert_eq!(events.len(), 3, "{events:?}");
+    assert_eq!(events[2]["suppressed"], 3);
+
+    Ok(())
+}
+
+/// A superuser session verifying JWTs signed by `sk`, which receives the audit
+/// events as NOTICEs.
+fn audited_client(
+    sk: &SigningKey,
+) -> Result<(postgres::Client, pgrx_tests::Notices), postgres::Error> {
+    let options = format!("-c {NEON_AUTH_JWK_RUNTIME_PARAM}={}", create_jwk(sk));
+    let (mut client, notices) =
+        pgrx_tests::superuser_client_with_notices(Some(&options)).expect("superuser connection");
+    client.batch_execute("SET pg_session_jwt.audit_log_level = notice")?;
+    Ok((client, notices))
+}
+
+fn audit_events(notices: &pgrx_tests::Notices) -> Vec<serde_json::Value> {
+    notices
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|notice| serde_json::from_str::<serde_json::Value>(notice).ok())
+        .filter(|event| event.get("event").is_some())
+        .collect()
+}
+
+fn test_stats(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    // the counters are shared with the other tests, so only look at what changed
+    fn count(
+        tx: &mut postgres::Client,
+        counter: &str,
+        reason: Option<&str>,
+    ) -> Result<i64, postgres::Error> {
+        tx.query_one(
+            "SELECT coalesce(sum(count), 0)::bigint FROM auth.stats \
+             WHERE counter = $1 AND reason IS NOT DISTINCT FROM $2",
+            &[&counter, &reason],
+        )
+        .map(|row| row.get(0))
+    }
+
+    let validations = count(tx, "validations", None)?;
+    let cache_hits = count(tx, "cache_hits", None)?;
+    let failures = count(tx, "failures", Some("invalid JWT signature"))?;
+
+    tx.execute("select auth.init()", &[])?;
+    let jwt = sign_jwt(sk, r#"{"kid":1}"#, r#"{"sub":"user1","jti":1}"#);
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+    tx.query_one("select auth.user_id()", &[])?;
+
+    // tamper with the signature
+    let forged = format!("{}AAAA", &jwt[..jwt.len() - 4]);
+    tx.execute("select auth.jwt_session_init($1)", &[&forged])
+        .unwrap_err();
+
+    assert_eq!(count(tx, "validations", None)?, validations + 1);
+    assert!(count(tx, "cache_hits", None)? > cache_hits);
+    assert_eq!(
+        count(tx, "failures", Some("invalid JWT signature"))?,
+        failures + 1
+    );
+    let sqlstate: String = tx
+        .query_one(
+            "SELECT sqlstate FROM auth.stats WHERE reason = 'invalid JWT signature'",
+            &[],
+        )?
+        .get(0);
+    assert_eq!(sqlstate, "28000");
+
+    // a claim rejected once the JWT was validated is counted too
+    let reason = "invalid subject claim in the JWT";
+    let subject_failures = count(tx, "failures", Some(reason))?;
+    let jwt = sign_jwt(sk, r#"{"kid":1}"#, r#"{"sub":42,"jti":2}"#);
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+    tx.query_one("select auth.user_id()", &[]).unwrap_err();
+    assert_eq!(count(tx, "failures", Some(reason))?, subject_failures + 1);
+
+    // only superusers can reset the counters
+    let err = tx.execute("select auth.stats_reset()", &[]).unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{err}");
+
+    Ok(())
+}
+
+fn test_not_preloaded(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    for query in [
+        "SELECT * FROM auth.stats",
+        "SELECT * FROM auth.active_sessions",
+    ] {
+        let err = tx.query(query, &[]).unwrap_err();
+        assert!(
+            err.to_string().contains("shared_preload_libraries"),
+            "{err}"
+        );
+    }
+
+    Ok(())
+}
+
+fn test_session_info(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    tx.execute("select auth.init()", &[])?;
+
+    let row = tx.query_one(
+        "SELECT mode, key_id, key_thumbprint, jti FROM auth.session_info()",
+        &[],
+    )?;
+    assert_eq!(row.get::<_, String>(0), "jwk");
+    assert_eq!(row.get::<_, Option<String>>(1), None);
+    assert_eq!(
+        row.get::<_, Option<String>>(2),
+        None,
+        "No key verified a JWT yet"
+    );
+    assert_eq!(row.get::<_, Option<i64>>(3), None);
+
+    let exp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 60;
+    let payload = json!({"sub": "user1", "jti": 3, "iat": 0, "exp": exp});
+    let jwt = sign_jwt(sk, r#"{"kid":"key-1"}"#, payload);
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+
+    let row = tx.query_one(
+        "SELECT mode, key_id, jti, extract(epoch FROM exp)::bigint, expires_in, \
+         validated_at IS NOT NULL, from_cache FROM auth.session_info()",
+        &[],
+    )?;
+    assert_eq!(row.get::<_, String>(0), "jwk");
+    assert_eq!(row.get::<_, Option<String>>(1).as_deref(), Some("key-1"));
+    assert_eq!(row.get::<_, Option<i64>>(2), Some(3));
+    assert_eq!(row.get::<_, Option<i64>>(3), Some(exp as i64));
+    let expires_in: f64 = row.get(4);
+    assert!(0.0 < expires_in && expires_in <= 60.0, "{expires_in}");
+    assert!(row.get::<_, bool>(5));
+    assert!(
+        row.get::<_, bool>(6),
+        "jwt_session_init already validated it"
+    );
+
+    Ok(())
+}
+
+fn test_session_info_before_init(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    let query = "SELECT key_id, key_thumbprint, jti, validated_at, from_cache \
+                 FROM auth.session_info()";
+    let assert_not_validated = |tx: &mut postgres::Client| -> Result<(), postgres::Error> {
+        let row = tx.query_one(query, &[])?;
+        assert_eq!(row.get::<_, Option<String>>(0), None);
+        assert_eq!(row.get::<_, Option<String>>(1), None);
+        assert_eq!(row.get::<_, Option<i64>>(2), None);
+        assert!(row.get::<_, Option<SystemTime>>(3).is_none());
+        assert!(!row.get::<_, bool>(4));
+        Ok(())
+    };
+    assert_not_validated(tx)?;
+
+    // reading the info doesn't validate a JWT which was only set
+    let jwt = sign_jwt(sk, r#"{"kid":"key-1"}"#, r#"{"sub":"user1","jti":1}"#);
+    tx.execute(&format!("SET pg_session_jwt.jwt = '{jwt}'"), &[])?;
+    assert_not_validated(tx)?;
+    assert_not_validated(tx)?;
+
+    // until the session is read
+    let sub: Option<String> = tx.query_one("SELECT auth.user_id()", &[])?.get(0);
+    assert_eq!(sub.as_deref(), Some("user1"));
+    let row = tx.query_one(query, &[])?;
+    assert_eq!(row.get::<_, Option<String>>(0).as_deref(), Some("key-1"));
+    assert!(row.get::<_, Option<String>>(1).is_some());
+    assert_eq!(row.get::<_, Option<i64>>(2), Some(1));
+    assert!(row.get::<_, bool>(4));
+
+    Ok(())
+}
+
+fn test_session_info_fallback(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let mode: String = tx
+        .query_one("SELECT mode FROM auth.session_info()", &[])?
+        .get(0);
+    assert_eq!(mode, "none");
+
+    tx.execute(
+        "SET request.jwt.claims = '{\"sub\":\"user1\",\"jti\":7}'",
+        &[],
+    )?;
+    let row = tx.query_one(
+        "SELECT mode, key_thumbprint, jti, validated_at IS NULL FROM auth.session_info()",
+        &[],
+    )?;
+    assert_eq!(row.get::<_, String>(0), "fallback");
+    assert_eq!(row.get::<_, Option<String>>(1), None);
+    assert_eq!(row.get::<_, Option<i64>>(2), Some(7));
+    assert!(row.get::<_, bool>(3));
+
+    Ok(())
+}
+
+fn test_active_sessions(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let query = "SELECT sub, jti, a.usename::text FROM auth.active_sessions \
+                 JOIN pg_stat_activity a USING (pid) WHERE pid = pg_backend_pid()";
+
+    tx.execute("select auth.init()", &[])?;
+    assert!(tx.query_opt(query, &[])?.is_none());
+
+    let jwt = sign_jwt(sk, r#"{"kid":1}"#, r#"{"sub":"user1","jti":1}"#);
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+    let row = tx.query_one(query, &[])?;
+    assert_eq!(row.get::<_, Option<String>>(0).as_deref(), Some("user1"));
+    assert_eq!(row.get::<_, i64>(1), 1);
+    assert_eq!(row.get::<_, String>(2), "pgrx");
+
+    let jwt = sign_jwt(sk, r#"{"kid":1}"#, r#"{"sub":"user2","jti":2}"#);
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+    let sub: Option<String> = tx.query_one(query, &[])?.get(0);
+    assert_eq!(sub.as_deref(), Some("user2"));
+
+    tx.execute("select auth.jwt_session_reset()", &[])?;
+    assert!(tx.query_opt(query, &[])?.is_none());
+
+    Ok(())
+}
+
+fn test_sqlstates(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    tx.execute("select auth.init()", &[])?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let header = r#"{"kid":1}"#;
+    let jwt = sign_jwt(sk, header, r#"{"jti":2}"#);
+    let forged = format!("{}AAAA", &jwt[..jwt.len() - 4]);
+    let expired = sign_jwt(sk, header, json!({"jti": 2, "exp": now - 60}));
+    let not_yet_valid = sign_jwt(sk, header, json!({"jti": 2, "nbf": now + 60}));
+    let cases = [
+        ("not a jwt".to_string(), "22P02"),
+        (sign_jwt(sk, header, r#"{"sub":"user1"}"#), "22P02"),
+        (forged, "28000"),
+        (expired, "28P01"),
+        (not_yet_valid, "55000"),
+    ];
+    for (jwt, sqlstate) in cases {
+        let err = tx
+            .execute("select auth.jwt_session_init($1)", &[&jwt])
+            .unwrap_err();
+        assert_eq!(err.code().map(|code| code.code()), Some(sqlstate), "{err}");
+    }
+
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+    let err = tx
+        .execute("select auth.jwt_session_init($1)", &[&jwt])
+        .unwrap_err();
+    assert_eq!(err.code().map(|code| code.code()), Some("23505"), "{err}");
+
+    Ok(())
+}
+
+fn test_validate_jwt(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    tx.execute("select auth.init()", &[])?;
+    let jwt = sign_jwt(sk, r#"{"kid":1}"#, r#"{"sub":"user1","jti":5}"#);
+
+    // the same JWT can be validated again, as the session isn't changed
+    for _ in 0..2 {
+        let row = tx.query_one(
+            "SELECT valid, reason, header->>'kid', payload->>'sub' FROM auth.validate_jwt($1)",
+            &[&jwt],
+        )?;
+        assert!(row.get::<_, bool>(0));
+        assert_eq!(row.get::<_, Option<String>>(1), None);
+        assert_eq!(row.get::<_, Option<String>>(2).as_deref(), Some("1"));
+        assert_eq!(row.get::<_, Option<String>>(3).as_deref(), Some("user1"));
+    }
+    let user_id: Option<String> = tx.query_one("SELECT auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, None, "Should not set the JWT of the session");
+
+    let forged = format!("{}AAAA", &jwt[..jwt.len() - 4]);
+    let row = tx.query_one(
+        "SELECT valid, reason, payload IS NULL FROM auth.validate_jwt($1)",
+        &[&forged],
+    )?;
+    assert!(!row.get::<_, bool>(0));
+    assert_eq!(
+        row.get::<_, Option<String>>(1).as_deref(),
+        Some("invalid JWT signature")
+    );
+    assert!(
+        row.get::<_, bool>(2),
+        "Should not return an unverified payload"
+    );
+
+    // the session still accepts the JWT
+    tx.execute("select auth.jwt_session_init($1)", &[&jwt])?;
+
+    Ok(())
+}
+
+fn test_jwt_decode(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    // the signature isn't verified, so any key will do
+    let sk = SigningKey::generate(&mut OsRng);
+    let jwt = sign_jwt(&sk, r#"{"kid":"key-1"}"#, r#"{"sub":"user1","jti":1}"#);
+
+    let row = tx.query_one(
+        "SELECT auth.jwt_decode_header($1)->>'kid', auth.jwt_decode_payload($1)->>'sub'",
+        &[&jwt],
+    )?;
+    assert_eq!(row.get::<_, Option<String>>(0).as_deref(), Some("key-1"));
+    assert_eq!(row.get::<_, Option<String>>(1).as_deref(), Some("user1"));
+
+    let err = tx
+        .query_one("SELECT auth.jwt_decode_payload('not a jwt')", &[])
+        .unwrap_err();
+    assert_eq!(err.code().map(|code| code.code()), Some("22P02"), "{err}");
+
+    let user_id: Option<String> = tx.query_one("SELECT auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id, None, "Should not set the JWT of the session");
+
+    Ok(())
+}
+
+fn test_jwt_sign_restricted(
+    _sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    // ordinary users must not be able to mint tokens
+    let err = tx
+        .query_one("SELECT auth.jwt_sign('{}', '{}')", &[])
+        .unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{err}");
+    let err = tx
+        .query_one("SELECT auth.issue_token('user1')", &[])
+        .unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{err}");
+    let err = tx
+        .query_one("SELECT count(*) FROM auth.signing_keys", &[])
+        .unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{err}");
+
+    Ok(())
+}
+
+fn test_jwt_sign(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let payload = json!({"sub": "user1", "jti": 1, "role": "authenticated"});
+    let private_jwk = create_private_jwk(sk);
+
+    // auth.jwt_sign() is restricted to superusers
+    let mut superuser = pgrx_tests::superuser_client().expect("superuser connection");
+    let jwt: String = superuser
+        .query_one(
+            "SELECT auth.jwt_sign($1::text::jsonb, $2::text::jsonb)",
+            &[&payload.to_string(), &private_jwk],
+        )?
+        .get(0);
+
+    tx.execute("SELECT auth.init()", &[])?;
+    tx.execute("SELECT auth.jwt_session_init($1)", &[&jwt])?;
+    assert_eq!(session(tx)?, payload);
+
+    // the JWK has no kid, so the header names it by its thumbprint
+    let row = tx.query_one(
+        "SELECT auth.jwt_decode_header($1)->>'kid', auth.jwk_thumbprint($2::text::jsonb)",
+        &[&jwt, &create_jwk(sk)],
+    )?;
+    assert_eq!(row.get::<_, Option<String>>(0), Some(row.get(1)));
+
+    Ok(())
+}
+
+fn test_issue_token(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let mut superuser = pgrx_tests::superuser_client().expect("superuser connection");
+    superuser.execute(
+        "INSERT INTO auth.signing_keys (kid, jwk, status, activated_at) \
+         VALUES ('key-1', $1::text::jsonb, 'active', now())",
+        &[&create_private_jwk(sk)],
+    )?;
+    let issued = superuser.query_one(
+        "SELECT auth.issue_token('user1', '{\"role\":\"authenticated\"}', '15 minutes'), \
+         floor(extract(epoch FROM now()))::bigint, currval('auth.token_id_seq')",
+        &[],
+    );
+    // the other tests must not find an active key
+    superuser.execute("DELETE FROM auth.signing_keys WHERE kid = 'key-1'", &[])?;
+    let issued = issued?;
+    let (jwt, now, jti): (String, i64, i64) = (issued.get(0), issued.get(1), issued.get(2));
+
+    tx.execute("SELECT auth.init()", &[])?;
+    tx.execute("SELECT auth.jwt_session_init($1)", &[&jwt])?;
+    assert_eq!(
+        session(tx)?,
+        json!({
+            "sub": "user1",
+            "role": "authenticated",
+            "iat": now,
+            "exp": now + 15 * 60,
+            "jti": jti,
+        })
+    );
+    let kid: Option<String> = tx
+        .query_one("SELECT key_id FROM auth.session_info()", &[])?
+        .get(0);
+    assert_eq!(kid.as_deref(), Some("key-1"));
+
+    Ok(())
+}
+
+fn test_jwks(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let row = tx.query_one(
+        "SELECT jsonb_array_length(jwks->'keys'), jwks->'keys'->0->>'x', \
+         jwks->'keys'->0->>'alg', jwks->'keys'->0->>'use' \
+         FROM auth.jwks() AS jwks",
+        &[],
+    )?;
+    assert_eq!(row.get::<_, i32>(0), 1);
+    let x = Base64UrlUnpadded::encode_string(sk.verifying_key().as_bytes());
+    assert_eq!(row.get::<_, Option<String>>(1), Some(x));
+    assert_eq!(row.get::<_, Option<String>>(2).as_deref(), Some("EdDSA"));
+    assert_eq!(row.get::<_, Option<String>>(3).as_deref(), Some("sig"));
+
+    // never publish the private half
+    let private: bool = tx
+        .query_one("SELECT auth.jwks()::text LIKE '%\"d\"%'", &[])?
+        .get(0);
+    assert!(!private);
+
+    Ok(())
+}
+
+fn test_jwks_without_jwk(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let keys: i32 = tx
+        .query_one("SELECT jsonb_array_length(auth.jwks()->'keys')", &[])?
+        .get(0);
+    assert_eq!(keys, 0);
+
+    Ok(())
+}
+
+fn test_jwk_thumbprint(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    // https://www.rfc-editor.org/rfc/rfc8037#appendix-A.3
+    let thumbprint: String = tx
+        .query_one(
+            r#"SELECT auth.jwk_thumbprint('{"kty":"OKP","crv":"Ed25519","x":"11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"}')"#,
+            &[],
+        )?
+        .get(0);
+    assert_eq!(thumbprint, "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k");
+
+    let err = tx
+        .query_one(r#"SELECT auth.jwk_thumbprint('{"kty":"OKP"}')"#, &[])
+        .unwrap_err();
+    assert_eq!(err.code().map(|code| code.code()), Some("22023"));
+
+    Ok(())
+}
+
+fn test_thumbprint_kid(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let jwk = create_jwk(sk);
+    let thumbprint: String = tx
+        .query_one("SELECT auth.jwk_thumbprint($1::text::jsonb)", &[&jwk])?
+        .get(0);
+
+    // keys without a kid are published and logged by their thumbprint
+    let kid: Option<String> = tx
+        .query_one("SELECT auth.jwks()->'keys'->0->>'kid'", &[])?
+        .get(0);
+    assert_eq!(kid.as_deref(), Some(thumbprint.as_str()));
+
+    let header = json!({ "kid": thumbprint }).to_string();
+    let jwt = sign_jwt(sk, &header, r#"{"sub":"user1","jti":1}"#);
+    tx.execute("SELECT auth.jwt_session_init($1)", &[&jwt])?;
+    let row = tx.query_one(
+        "SELECT key_id, key_thumbprint FROM auth.session_info()",
+        &[],
+    )?;
+    assert_eq!(
+        row.get::<_, Option<String>>(0).as_deref(),
+        Some(thumbprint.as_str())
+    );
+    assert_eq!(
+        row.get::<_, Option<String>>(1).as_deref(),
+        Some(thumbprint.as_str())
+    );
+
+    Ok(())
+}
+
+fn test_jwk_metadata(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let reason = |tx: &mut postgres::Client, header: &str| {
+        let jwt = sign_jwt(sk, header, r#"{"sub":"user1","jti":1}"#);
+        tx.query_one("SELECT reason FROM auth.validate_jwt($1)", &[&jwt])
+            .map(|row| row.get::<_, Option<String>>(0))
+    };
+
+    assert_eq!(reason(tx, r#"{"alg":"EdDSA","kid":"key-1"}"#)?, None);
+    assert_eq!(
+        reason(tx, r#"{"alg":"none","kid":"key-1"}"#)?.as_deref(),
+        Some("JWT 'alg' does not match the JWK")
+    );
+    assert_eq!(
+        reason(tx, r#"{"kid":"key-1"}"#)?.as_deref(),
+        Some("JWT 'alg' does not match the JWK")
+    );
+    assert_eq!(
+        reason(tx, r#"{"alg":"EdDSA","kid":"key-2"}"#)?.as_deref(),
+        Some("JWT 'kid' does not match the JWK")
+    );
+
+    let alg: Option<String> = tx
+        .query_one("SELECT auth.jwks()->'keys'->0->>'alg'", &[])?
+        .get(0);
+    assert_eq!(alg.as_deref(), Some("EdDSA"));
+
+    Ok(())
+}
+
+fn test_jwk_other_members(
+    sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    // members which aren't honored, like the X.509 thumbprint, are ignored
+    let jwt = sign_jwt(sk, r#"{"kid":"key-1"}"#, r#"{"sub":"user1","jti":1}"#);
+    tx.execute("SELECT auth.jwt_session_init($1)", &[&jwt])?;
+    let user_id: Option<String> = tx.query_one("SELECT auth.user_id()", &[])?.get(0);
+    assert_eq!(user_id.as_deref(), Some("user1"));
+
+    Ok(())
+}
+
+fn test_jwk_encryption_key(
+    _sk: &SigningKey,
+    tx: &mut postgres::Client,
+) -> Result<(), postgres::Error> {
+    tx.execute("SELECT auth.init()", &[])?;
+
+    Ok(())
+}
+
+fn test_jwk_expired(sk: &SigningKey, tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    // expired keys aren't published anymore
+    let keys: i32 = tx
+        .query_one("SELECT jsonb_array_length(auth.jwks()->'keys')", &[])?
+        .get(0);
+    assert_eq!(keys, 0);
+
+    let jwt = sign_jwt(sk, r#"{"kid":1}"#, r#"{"jti":1}"#);
+    tx.execute("SELECT auth.jwt_session_init($1)", &[&jwt])?;
+
+    Ok(())
+}
+
+fn test_jwks_file_setting(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let path: String = tx.query_one("SHOW pg_session_jwt.jwks_file", &[])?.get(0);
+    assert_eq!(path, "");
+
+    // without keys, the claims still come from request.jwt.claims
+    let mode: String = tx
+        .query_one("SELECT mode FROM auth.session_info()", &[])?
+        .get(0);
+    assert_eq!(mode, "none");
+
+    // the file is only set in the configuration files
+    let err = tx
+        .execute("SET pg_session_jwt.jwks_file = 'jwks.json'", &[])
+        .unwrap_err();
+    assert!(err.to_string().contains("cannot be changed now"), "{err}");
+
+    Ok(())
+}
+
+fn test_jwks_file_reload(_tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    // the file is read by the backend, which reports the WARNING to its client
+    let (mut client, notices) =
+        pgrx_tests::superuser_client_with_notices(None).expect("superuser connection");
+    let data_dir: String = client.query_one("SHOW data_directory", &[])?.get(0);
+    let path = std::path::Path::new(&data_dir).join("test_jwks.json");
+
+    let sk = SigningKey::generate(&mut OsRng);
+    let jwk: serde_json::Value = serde_json::from_str(&create_jwk(&sk)).unwrap();
+    std::fs::write(&path, json!({"keys": [jwk]}).to_string()).unwrap();
+    let _setting = ClusterSetting::set("pg_session_jwt.jwks_file", "test_jwks.json")?;
+
+    let x = Base64UrlUnpadded::encode_string(sk.verifying_key().as_bytes());
+    let published = |client: &mut postgres::Client| -> Result<bool, postgres::Error> {
+        let published: Option<String> = client
+            .query_one("SELECT auth.jwks()->'keys'->0->>'x'", &[])?
+            .get(0);
+        Ok(published.as_deref() == Some(x.as_str()))
+    };
+    let jwt = sign_jwt(&sk, r#"{"kid":1}"#, r#"{"sub":"user1","jti":1}"#);
+    let valid = |client: &mut postgres::Client| -> Result<bool, postgres::Error> {
+        client
+            .query_one("SELECT valid FROM auth.validate_jwt($1)", &[&jwt])
+            .map(|row| row.get(0))
+    };
+    // the backend reads the file once it handled the reload
+    wait_for(|| published(&mut client))?;
+    assert!(valid(&mut client)?);
+
+    std::fs::write(&path, "{\"keys\": [").unwrap();
+    client.batch_execute("SELECT pg_reload_conf()")?;
+    let warned = |notices: &pgrx_tests::Notices| {
+        notices.lock().unwrap().iter().any(|notice| {
+            notice.contains("could not load pg_session_jwt.jwks_file, keeping the previous keys")
+        })
+    };
+    wait_for(|| {
+        client.query_one("SELECT auth.jwks()", &[])?;
+        Ok(warned(&notices))
+    })?;
+    assert!(published(&mut client)?);
+    assert!(valid(&mut client)?);
+
+    std::fs::remove_file(&path).unwrap();
+
+    Ok(())
+}
+
+fn test_verification_keys_table(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let enabled: String = tx.query_one("SHOW pg_session_jwt.jwks_table", &[])?.get(0);
+    assert_eq!(enabled, "off");
+
+    // the public keys can be read by anybody, like auth.jwks()
+    let keys: i64 = tx
+        .query_one("SELECT count(*) FROM auth.verification_keys", &[])?
+        .get(0);
+    assert_eq!(keys, 0);
+
+    // ordinary users must not be able to trust their own keys
+    let err = tx
+        .execute("SET pg_session_jwt.jwks_table = on", &[])
+        .unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{err}");
+    let err = tx
+        .execute(
+            "INSERT INTO auth.verification_keys (kid, jwk) VALUES ('key-1', '{}')",
+            &[],
+        )
+        .unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{err}");
+
+    // rotate the keys from another session
+    let mut admin = pgrx_tests::superuser_client().expect("superuser connection");
+    let (mut client, _) =
+        pgrx_tests::superuser_client_with_notices(Some("-c pg_session_jwt.jwks_table=on"))
+            .expect("superuser connection");
+    let sk1 = SigningKey::generate(&mut OsRng);
+    let sk2 = SigningKey::generate(&mut OsRng);
+    admin.execute(
+        "INSERT INTO auth.verification_keys (kid, jwk) \
+         VALUES ('key-1', $1::text::jsonb), ('key-2', $2::text::jsonb)",
+        &[&create_jwk(&sk1), &create_jwk(&sk2)],
+    )?;
+    let jwt = sign_jwt(&sk1, r#"{"kid":"key-1"}"#, r#"{"sub":"user1","jti":1}"#);
+    let valid = |client: &mut postgres::Client| -> Result<bool, postgres::Error> {
+        client
+            .query_one("SELECT valid FROM auth.validate_jwt($1)", &[&jwt])
+            .map(|row| row.get(0))
+    };
+    assert!(valid(&mut client)?);
+    assert!(valid(&mut client)?, "Should keep the cached keys");
+
+    // the cached keys are invalidated once the DELETE commits
+    admin.execute(
+        "DELETE FROM auth.verification_keys WHERE kid = 'key-1'",
+        &[],
+    )?;
+    let rejected = !valid(&mut client)?;
+    admin.execute("DELETE FROM auth.verification_keys", &[])?;
+    assert!(rejected, "Should reject the JWT of the deleted key");
+
+    Ok(())
+}
+
+fn test_jwks_uri(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let sk = SigningKey::generate(&mut OsRng);
+    let jwk: serde_json::Value = serde_json::from_str(&create_jwk(&sk)).unwrap();
+    let jwks = json!({"keys": [jwk]}).to_string();
+    // the keys must be fetched again after a second, when the server fails
+    let (base, requests) = serve(move |_, _, i| match i {
+        0 => (200, jwks.clone()),
+        _ => (500, "{}".to_string()),
+    });
+    // the stub server doesn't speak TLS
+    let _http = ClusterSetting::set("pg_session_jwt.jwks_allow_http", "on")?;
+    let _setting = ClusterSetting::set("pg_session_jwt.jwks_uri", &format!("{base}/jwks.json"))?;
+
+    let x = Base64UrlUnpadded::encode_string(sk.verifying_key().as_bytes());
+    let published = |tx: &mut postgres::Client| -> Result<bool, postgres::Error> {
+        let published: Option<String> = tx
+            .query_one("SELECT auth.jwks()->'keys'->0->>'x'", &[])?
+            .get(0);
+        Ok(published.as_deref() == Some(x.as_str()))
+    };
+    wait_for(|| published(tx))?;
+
+    let jwt = sign_jwt(&sk, r#"{"kid":1}"#, r#"{"sub":"user1","jti":1}"#);
+    let valid: bool = tx
+        .query_one("SELECT valid FROM auth.validate_jwt($1)", &[&jwt])?
+        .get(0);
+    assert!(valid);
+
+    // the previous keys are kept
+    wait_for(|| Ok(requests.load(Ordering::SeqCst) >= 2))?;
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(published(tx)?);
+
+    Ok(())
+}
+
+fn test_jwks_uri_requires_https(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let (base, requests) = serve(|_, _, _| (200, r#"{"keys":[]}"#.to_string()));
+    let _setting = ClusterSetting::set("pg_session_jwt.jwks_uri", &format!("{base}/jwks.json"))?;
+
+    // the worker is started by the first session which needs the keys
+    tx.query_one("SELECT auth.jwks()", &[])?;
+    std::thread::sleep(Duration::from_secs(2));
+    assert_eq!(
+        requests.load(Ordering::SeqCst),
+        0,
+        "Should not fetch over plain HTTP"
+    );
+
+    Ok(())
+}
+
+fn test_oidc_discovery(tx: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let sk = SigningKey::generate(&mut OsRng);
+    let jwk: serde_json::Value = serde_json::from_str(&create_jwk(&sk)).unwrap();
+    let jwks = json!({"keys": [jwk]}).to_string();
+    let issuer = "https://issuer.example.com";
+    let (base, _) = serve(move |base, path, _| match path {
+        "/.well-known/openid-configuration" => {
+            let discovery = json!({
+                "issuer": issuer,
+                "jwks_uri": format!("{base}/jwks.json"),
+                "id_token_signing_alg_values_supported": ["RS256", "EdDSA"],
+            });
+            (200, discovery.to_string())
+        }
+        "/jwks.json" => (200, jwks.clone()),
+        _ => (404, "{}".to_string()),
+    });
+    let _http = ClusterSetting::set("pg_session_jwt.jwks_allow_http", "on")?;
+    let _setting = ClusterSetting::set(
+        "pg_session_jwt.oidc_discovery",
+        &format!("{base}/.well-known/openid-configuration"),
+    )?;
+
+    // the keys are found through the discovery document
+    let x = Base64UrlUnpadded::encode_string(sk.verifying_key().as_bytes());
+    wait_for(|| {
+        let published: Option<String> = tx
+            .query_one("SELECT auth.jwks()->'keys'->0->>'x'", &[])?
+            .get(0);
+        Ok(published.as_deref() == Some(x.as_str()))
+    })?;
+
+    let header = r#"{"alg":"EdDSA"}"#;
+    let valid = json!({"jti": 1, "iss": issuer});
+    let other_issuer = json!({"jti": 1, "iss": "https://evil.example.com"});
+    let cases = [
+        (sign_jwt(&sk, header, &valid), None),
+        (
+            sign_jwt(&sk, header, &other_issuer),
+            Some("JWT 'iss' does not match the issuer"),
+        ),
+        (
+            sign_jwt(&sk, header, json!({"jti": 1})),
+            Some("JWT 'iss' does not match the issuer"),
+        ),
+        (
+            sign_jwt(&sk, r#"{"alg":"HS256"}"#, &valid),
+            Some("JWT 'alg' is not supported by the issuer"),
+        ),
+    ];
+    for (jwt, reason) in cases {
+        let row = tx.query_one("SELECT valid, reason FROM auth.validate_jwt($1)", &[&jwt])?;
+        assert_eq!(row.get::<_, bool>(0), reason.is_none());
+        assert_eq!(row.get::<_, Option<String>>(1).as_deref(), reason);
+    }
+
+    tx.execute("SELECT auth.init()", &[])?;
+    let jwt = sign_jwt(&sk, header, &other_issuer);
+    let err = tx
+        .execute("SELECT auth.jwt_session_init($1)", &[&jwt])
+        .unwrap_err();
+    assert_eq!(err.code().map(|code| code.code()), Some("42501"), "{err}");
+
+    Ok(())
+}
+
+/// Sets a parameter for the whole cluster, until dropped.
+struct ClusterSetting {
+    client: postgres::Client,
+    name: &'static str,
+}
+
+impl ClusterSetting {
+    fn set(name: &'static str, value: &str) -> Result<Self, postgres::Error> {
+        let mut client = pgrx_tests::superuser_client().expect("superuser connection");
+        // ALTER SYSTEM can't be part of a multi-statement query
+        client.batch_execute(&format!("ALTER SYSTEM SET {name} = '{value}'"))?;
+        client.batch_execute("SELECT pg_reload_conf()")?;
+        Ok(Self { client, name })
+    }
+}
+
+impl Drop for ClusterSetting {
+    fn drop(&mut self) {
+        let _ = self
+            .client
+            .batch_execute(&format!("ALTER SYSTEM RESET {}", self.name));
+        let _ = self.client.batch_execute("SELECT pg_reload_conf()");
+        // let the postmaster reload the configuration before the next test
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Serve the responses built from the base URL, the path and the index of
+/// each request, on a local port. Returns the base URL and the number of
+/// requests served.
+fn serve<F>(respond: F) -> (String, Arc<AtomicUsize>)
+where
+    F: Fn(&str, &str, usize) -> (u16, String) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let served = requests.clone();
+    let url = base.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            // the path of the request line, skipping the headers
+            let mut path = String::new();
+            for line in BufReader::new(&stream).lines() {
+                let Ok(line) = line else { break };
+                if line.is_empty() {
+                    break;
+                }
+                if path.is_empty() {
+                    path = line.split(' ').nth(1).unwrap_or("/").to_string();
+                }
+            }
+            let i = served.fetch_add(1, Ordering::SeqCst);
+            let (status, body) = respond(&url, &path, i);
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {status} Stub\r\nContent-Type: application/json\r\n\
+                 Cache-Control: max-age=1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+        }
+    });
+
+    (base, requests)
+}
+
+fn wait_for(
+    mut condition: impl FnMut() -> Result<bool, postgres::Error>,
+) -> Result<(), postgres::Error> {
+    for _ in 0..100 {
+        if condition()? {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    panic!("timed out");
+}
+
+static NEON_AUTH_JWK_RUNTIME_PARAM: &str = "pg_session_jwt.jwk";
+
+/// A JWK with metadata, as found in JWK Sets.
+fn create_jwk_with(sk: &SigningKey, members: serde_json::Value) -> String {
+    let mut jwk: serde_json::Value = serde_json::from_str(&create_jwk(sk)).unwrap();
+    jwk.as_object_mut()
+        .unwrap()
+        .extend(members.as_object().unwrap().clone());
+    jwk.to_string()
+}
+
+fn create_bound_jwk(sk: &SigningKey) -> String {
+    create_jwk_with(
+        sk,
+        json!({"kid": "key-1", "use": "sig", "key_ops": ["verify"], "alg": "EdDSA"}),
+    )
+}
+
+fn create_jwk_with_x5t(sk: &SigningKey) -> String {
+    create_jwk_with(
+        sk,
+        json!({"kid": "key-1", "x5t": "dGhpcyBpcyBub3QgYSBjZXJ0aWZpY2F0ZQ"}),
+    )
+}
+
+fn create_encryption_jwk(sk: &SigningKey) -> String {
+    create_jwk_with(sk, json!({"use": "enc"}))
+}
+
+fn create_expired_jwk(sk: &SigningKey) -> String {
+    create_jwk_with(sk, json!({"exp": 1}))
+}
+
+fn sign_jwt(sk: &SigningKey, header: &str, payload: impl ToString) -> String {
+    let header = Base64UrlUnpadded::encode_string(header.as_bytes());
+    let payload = Base64UrlUnpadded::encode_string(payload.to_string().as_bytes());
+
+    let message = format!("{header}.{payload}");
+    let sig: Signature = sk.sign(message.as_bytes());
+    let base64_sig = Base64UrlUnpadded::encode_string(&sig.to_bytes());
+    format!("{message}.{base64_sig}")
+}
+
+fn create_private_jwk(sk: &SigningKey) -> String {
+    let key = jose_jwk::Key::Okp(Okp {
+        crv: jose_jwk::OkpCurves::Ed25519,
+        x: jose_b64::serde::Bytes::from(sk.verifying_key().to_bytes().to_vec()),
+        d: Some(jose_b64::serde::Secret::from(sk.to_bytes().to_vec())),
+    });
+    serde_json::to_string(&key).unwrap()
 }
 
 fn create_jwk(sk: &SigningKey) -> String {
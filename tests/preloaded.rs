@@ -0,0 +1,2 @@
+// The same tests, with the extension loaded via `shared_preload_libraries`.
+include!("pg_session_jwt.rs");